@@ -0,0 +1,415 @@
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::OnceCell;
+
+use itertools::Itertools;
+
+use crate::env::{env_get_sym, Env};
+use crate::symbol::{SpecialForm, Symbol};
+use crate::types::MalVal::{Hash, List, MalFunc, Nil, Set, Sym, Vector};
+use crate::types::{nesting_error, syntax_error, FuncStruct, MalErr, MalVal, MAX_NESTING};
+use crate::quasiquote;
+
+// Bytecode compiler: turns a form into a flat `Chunk` of instructions for
+// the stack VM in `vm.rs`. Special forms are resolved here once, and calls
+// to macros bound in the environment at compile time are expanded here too;
+// calls whose head only turns out to be a macro at run time are expanded by
+// the VM (see `Op::MacroCheck`).
+//
+// Locals use lexical addressing: every `fn*` call, `let*` and `catch*`
+// creates one environment, whose bindings the compiler tracks as a scope.
+// A symbol found in a scope compiles to a (depth, index) slot reference;
+// anything else is a global looked up by name.
+
+/// A compiled form.
+pub struct Chunk {
+    pub(crate) code: Vec<Op>,
+}
+
+/// Compiled body of a function, filled in on first use and shared by every
+/// closure created from the same `fn*` form.
+#[derive(Default)]
+pub struct Code(OnceCell<Rc<Chunk>>);
+
+/// A `fn*` form with its body already compiled.
+pub(crate) struct Lambda {
+    pub params: Rc<MalVal>,
+    pub ast: Rc<MalVal>,
+    pub code: Rc<Code>,
+}
+
+pub(crate) enum Op {
+    /// Push a constant.
+    Const(MalVal),
+    /// Push the value bound to a symbol.
+    Load(Symbol),
+    /// Push slot `index` of the environment `depth` levels out.
+    Local(usize, usize),
+    /// Bind the value on top of the stack in the current environment,
+    /// leaving it on the stack (`def!`). Locals are stored in the given
+    /// slot rather than by name.
+    Def(MalVal, Option<usize>),
+    /// Like `Def`, marking the function on top of the stack as a macro.
+    DefMacro(MalVal, Option<usize>),
+    /// Pop a value and bind it in the current environment (`let*`).
+    Bind(MalVal),
+    /// Pop a value into a slot of the current environment (`let*`).
+    SetLocal(usize),
+    Pop,
+    Jump(usize),
+    /// Pop a value and jump if it is `nil` or `false`.
+    JumpIfFalse(usize),
+    /// Open a new environment nested in the current one.
+    EnterScope,
+    /// Return to the environment active before the matching `EnterScope`.
+    ExitScope,
+    /// Push a closure over the current environment.
+    Lambda(Rc<Lambda>),
+    /// Pop that many values into a vector.
+    Vector(usize),
+    /// Pop that many keys and values, alternating, into a hash map.
+    Hash(usize),
+    /// Pop that many values into a set.
+    Set(usize),
+    /// If the callee on top of the stack is a macro, expand the call form,
+    /// evaluate the expansion in the scopes of the call, push its value and
+    /// jump past the call.
+    MacroCheck(MalVal, Rc<Scopes>, usize),
+    /// Pop that many arguments and the callee and call it. The form is kept
+    /// for stack traces.
+    Call(usize, MalVal),
+    /// Like `Call`, reusing the current frame for a function callee.
+    TailCall(usize, MalVal),
+    /// Install a `catch*` handler starting at the given instruction.
+    Try(usize),
+    /// Remove the innermost handler.
+    EndTry,
+    /// Open a scope binding the caught error to the symbol, in slot 0 (and
+    /// `*stack-trace*` to its trace).
+    Catch(MalVal),
+    /// Return the value on top of the stack from the current frame.
+    Return,
+}
+
+/// Names bound in each environment enclosing a form, innermost last; slot
+/// indices are positions within a scope.
+pub(crate) type Scopes = Vec<Vec<Symbol>>;
+
+struct Compiler<'a> {
+    env: &'a Env,
+    code: Vec<Op>,
+    scopes: Scopes,
+    // Number of outer scopes whose layout is already fixed: a runtime macro
+    // expansion runs in the caller's environments, so a `def!` of a new
+    // name there binds by name instead of taking a slot the caller may use.
+    fixed: usize,
+    // Forms being compiled around the current one, up to `MAX_NESTING`.
+    depth: usize,
+    // Shared copy of `scopes` for `MacroCheck`, until they next change.
+    snapshot: Option<Rc<Scopes>>,
+}
+
+/// Compiles a top-level form evaluated in `env`.
+pub fn compile(ast: &MalVal, env: &Env) -> Result<Rc<Chunk>, MalErr> {
+    compile_in(ast, env, vec![])
+}
+
+/// Returns the compiled body of `f`, compiling it on first use.
+pub fn function_code(f: &FuncStruct) -> Result<Rc<Chunk>, MalErr> {
+    if let Some(chunk) = f.code.0.get() {
+        return Ok(chunk.clone());
+    }
+    let chunk = compile_in(&f.ast, &f.env, vec![param_names(&f.params)])?;
+    Ok(f.code.0.get_or_init(|| chunk).clone())
+}
+
+/// Compiles a form evaluated in an environment laid out as `scopes`.
+pub(crate) fn compile_in(ast: &MalVal, env: &Env, scopes: Scopes) -> Result<Rc<Chunk>, MalErr> {
+    compile_with(ast, env, scopes, 0, 0)
+}
+
+/// Compiles the expansion of a macro call found at run time, evaluated in
+/// the environments of the call, laid out as `scopes`.
+pub(crate) fn compile_expansion(ast: &MalVal, env: &Env, scopes: Scopes) -> Result<Rc<Chunk>, MalErr> {
+    let fixed = scopes.len();
+    compile_with(ast, env, scopes, fixed, 0)
+}
+
+fn compile_with(ast: &MalVal, env: &Env, scopes: Scopes, fixed: usize, depth: usize) -> Result<Rc<Chunk>, MalErr> {
+    let mut c = Compiler {
+        env,
+        code: vec![],
+        scopes,
+        fixed,
+        depth,
+        snapshot: None,
+    };
+    c.form(ast, true)?;
+    c.code.push(Op::Return);
+    Ok(Rc::new(Chunk { code: c.code }))
+}
+
+fn param_names(params: &MalVal) -> Vec<Symbol> {
+    match params.as_seq() {
+        Some(ps) => ps
+            .iter()
+            .filter_map(|p| match p {
+                Sym(s) if s != "&" => Some(*s),
+                _ => None,
+            })
+            .collect(),
+        None => vec![],
+    }
+}
+
+// Checks that a special form has at least `n` elements, itself included.
+fn expect(l: &[MalVal], n: usize, name: &str) -> Result<(), MalErr> {
+    if l.len() < n {
+        return syntax_error(&alloc::format!("{} expects {} arguments", name, n - 1));
+    }
+    Ok(())
+}
+
+impl Compiler<'_> {
+    fn here(&self) -> usize {
+        self.code.len()
+    }
+
+    fn resolve(&self, name: Symbol) -> Option<(usize, usize)> {
+        self.scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| Some((depth, scope.iter().rposition(|&s| s == name)?)))
+    }
+
+    fn enter(&mut self, scope: Vec<Symbol>) {
+        self.scopes.push(scope);
+        self.snapshot = None;
+    }
+
+    fn leave(&mut self) {
+        self.scopes.pop();
+        self.snapshot = None;
+    }
+
+    // Adds a binding to the innermost scope, returning its slot.
+    fn declare(&mut self, name: Symbol) -> usize {
+        let scope = self.scopes.last_mut().unwrap();
+        scope.push(name);
+        self.snapshot = None;
+        scope.len() - 1
+    }
+
+    // Slot a `def!` of `key` writes to, if any: the innermost binding of
+    // that name, or a new one when the name shadows a local of an enclosing
+    // scope. Any other name is defined by name in the current environment,
+    // as the AST walker does, so code reading it when the `def!` has not run
+    // (say, in an `if` branch not taken) still finds the global. The one
+    // difference: a shadowing slot reads as `nil` until its `def!` runs,
+    // where the AST walker reads the enclosing local. Scopes whose layout is
+    // fixed take no new slots.
+    fn def_slot(&mut self, key: &MalVal) -> Option<usize> {
+        match (key, self.scopes.last()) {
+            (Sym(s), Some(scope)) => match scope.iter().rposition(|n| n == s) {
+                Some(i) => Some(i),
+                None if self.scopes.len() > self.fixed && self.resolve(*s).is_some() => Some(self.declare(*s)),
+                None => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn scopes(&mut self) -> Rc<Scopes> {
+        self.snapshot.get_or_insert_with(|| Rc::new(self.scopes.clone())).clone()
+    }
+
+    // Points the jump at `at` to the next instruction emitted.
+    fn patch(&mut self, at: usize) {
+        let here = self.here();
+        match &mut self.code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) | Op::Try(t) | Op::MacroCheck(_, _, t) => *t = here,
+            _ => unreachable!(),
+        }
+    }
+
+    fn form(&mut self, ast: &MalVal, tail: bool) -> Result<(), MalErr> {
+        if self.depth >= MAX_NESTING {
+            return nesting_error();
+        }
+        self.depth += 1;
+        let res = self.nested_form(ast, tail);
+        self.depth -= 1;
+        res
+    }
+
+    fn nested_form(&mut self, ast: &MalVal, tail: bool) -> Result<(), MalErr> {
+        match ast {
+            Sym(s) => match self.resolve(*s) {
+                Some((depth, index)) => self.code.push(Op::Local(depth, index)),
+                None => self.code.push(Op::Load(*s)),
+            },
+            Vector(v, _) => {
+                for a in v.iter() {
+                    self.form(a, false)?;
+                }
+                self.code.push(Op::Vector(v.len()));
+            }
+            Hash(hm, _) => {
+                for (k, v) in hm.iter() {
+                    self.form(k, false)?;
+                    self.form(v, false)?;
+                }
+                self.code.push(Op::Hash(hm.size()));
+            }
+            Set(s, _) => {
+                for v in s.iter() {
+                    self.form(v, false)?;
+                }
+                self.code.push(Op::Set(s.size()));
+            }
+            List(l, _) if !l.is_empty() => self.list(ast, l, tail)?,
+            _ => self.code.push(Op::Const(ast.clone())),
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, ast: &MalVal, l: &[MalVal], tail: bool) -> Result<(), MalErr> {
+        if let Sym(a0) = &l[0] {
+            match a0.special() {
+                Some(SpecialForm::Def) => {
+                    expect(l, 3, a0)?;
+                    self.form(&l[2], false)?;
+                    let slot = self.def_slot(&l[1]);
+                    self.code.push(Op::Def(l[1].clone(), slot));
+                    return Ok(());
+                }
+                Some(SpecialForm::Let) => {
+                    expect(l, 3, a0)?;
+                    let binds = match l[1].as_seq() {
+                        Some(binds) => binds,
+                        None => return syntax_error("let* with non-List bindings"),
+                    };
+                    self.code.push(Op::EnterScope);
+                    self.enter(vec![]);
+                    for (b, e) in binds.iter().tuples() {
+                        self.form(e, false)?;
+                        match b {
+                            Sym(s) => {
+                                let slot = self.declare(*s);
+                                self.code.push(Op::SetLocal(slot));
+                            }
+                            _ => self.code.push(Op::Bind(b.clone())),
+                        }
+                    }
+                    self.form(&l[2], tail)?;
+                    self.leave();
+                    self.code.push(Op::ExitScope);
+                    return Ok(());
+                }
+                Some(SpecialForm::Quote) => {
+                    expect(l, 2, a0)?;
+                    self.code.push(Op::Const(l[1].clone()));
+                    return Ok(());
+                }
+                Some(SpecialForm::Quasiquote) => {
+                    expect(l, 2, a0)?;
+                    return self.form(&quasiquote(&l[1])?, tail);
+                }
+                Some(SpecialForm::DefMacro) => {
+                    expect(l, 3, a0)?;
+                    self.form(&l[2], false)?;
+                    let slot = self.def_slot(&l[1]);
+                    self.code.push(Op::DefMacro(l[1].clone(), slot));
+                    return Ok(());
+                }
+                Some(SpecialForm::Try) => {
+                    expect(l, 2, a0)?;
+                    if l.len() < 3 {
+                        return self.form(&l[1], tail);
+                    }
+                    let (sym, handler) = match &l[2] {
+                        List(c, _) if c.len() >= 3 => (&c[1], &c[2]),
+                        _ => return syntax_error("invalid catch block"),
+                    };
+                    let install = self.here();
+                    self.code.push(Op::Try(0));
+                    self.form(&l[1], false)?;
+                    self.code.push(Op::EndTry);
+                    let done = self.here();
+                    self.code.push(Op::Jump(0));
+                    self.patch(install);
+                    self.code.push(Op::Catch(sym.clone()));
+                    self.enter(match sym {
+                        Sym(s) => vec![*s],
+                        _ => vec![],
+                    });
+                    self.form(handler, false)?;
+                    self.leave();
+                    self.code.push(Op::ExitScope);
+                    self.patch(done);
+                    return Ok(());
+                }
+                Some(SpecialForm::Do) => {
+                    if l.len() == 1 {
+                        self.code.push(Op::Const(Nil));
+                        return Ok(());
+                    }
+                    for a in &l[1..l.len() - 1] {
+                        self.form(a, false)?;
+                        self.code.push(Op::Pop);
+                    }
+                    return self.form(&l[l.len() - 1], tail);
+                }
+                Some(SpecialForm::If) => {
+                    expect(l, 2, a0)?;
+                    self.form(&l[1], false)?;
+                    let otherwise = self.here();
+                    self.code.push(Op::JumpIfFalse(0));
+                    self.form(l.get(2).unwrap_or(&Nil), tail)?;
+                    let done = self.here();
+                    self.code.push(Op::Jump(0));
+                    self.patch(otherwise);
+                    self.form(l.get(3).unwrap_or(&Nil), tail)?;
+                    self.patch(done);
+                    return Ok(());
+                }
+                Some(SpecialForm::Fn) => {
+                    expect(l, 3, a0)?;
+                    let mut scopes = self.scopes.clone();
+                    scopes.push(param_names(&l[1]));
+                    let chunk = compile_with(&l[2], self.env, scopes, 0, self.depth)?;
+                    self.code.push(Op::Lambda(Rc::new(Lambda {
+                        params: Rc::new(l[1].clone()),
+                        ast: Rc::new(l[2].clone()),
+                        code: Rc::new(Code(OnceCell::from(chunk))),
+                    })));
+                    return Ok(());
+                }
+                _ if self.resolve(*a0).is_none() => {
+                    if let Some(m @ MalFunc(FuncStruct { is_macro: true, .. })) = env_get_sym(self.env, *a0) {
+                        let expansion = m.apply(l[1..].to_vec())?;
+                        return self.form(&expansion, tail);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.form(&l[0], false)?;
+        let check = self.here();
+        let scopes = self.scopes();
+        self.code.push(Op::MacroCheck(ast.clone(), scopes, 0));
+        for a in &l[1..] {
+            self.form(a, false)?;
+        }
+        let argc = l.len() - 1;
+        self.code.push(match tail {
+            true => Op::TailCall(argc, ast.clone()),
+            false => Op::Call(argc, ast.clone()),
+        });
+        self.patch(check);
+        Ok(())
+    }
+}
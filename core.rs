@@ -1,19 +1,26 @@
 use alloc::rc::Rc;
 use core::cell::RefCell;
 use alloc::vec;
+use alloc::format;
 use alloc::vec::Vec;
 use alloc::string::{String, ToString};
+use core::cmp::Ordering;
+
+use itertools::Itertools;
+use num_traits::FromPrimitive;
 
 use crate::MalVal::NativeClosure;
 use crate::printer::pr_seq;
-use crate::reader::read_str;
+use crate::reader::{parse_number, read_str};
+use crate::symbol::Symbol;
+use crate::env::Interrupt;
 
 use crate::types::MalVal::{
-    Atom, Bool, Func, Hash, Int, Float, Kwd, List, MalFunc, Nil, Str, Sym, Vector,
+    Atom, BigInt, Bool, Char, Func, Hash, Int, Float, Kwd, List, MalFunc, Nil, Ratio, Set, Str, Sym, Vector,
 };
 use crate::types::{
-    list, FuncStruct, MalArgs, MalRet, MalVal, _assoc, error, func, hash_map, unwrap_map_key,
-    vector, wrap_map_key,
+    list, bigint, bigint_to_float, float_to_f64, ratio, ratio_to_float, FuncStruct, MalArgs, MalBigInt, MalErr, MalFloat, MalRatio, MalRet, MalSet, MalVal, _assoc, arity_error, error, func, hash_map,
+    func_closure, new_map, new_set, type_error, vector, MalMap, MalVector,
 };
 
 // macro_rules! fn_t_int_int {
@@ -47,11 +54,11 @@ macro_rules! fn_str {
     ($fn:expr) => {{
         |a: MalArgs| {
             if a.len() != 1 {
-                return error("expecting exactly 1 arg");
+                return $crate::types::arity_error("expecting exactly 1 arg");
             }
             match &a[0] {
                 Str(a0) => $fn(&a0),
-                _ => error("expecting (str) arg"),
+                _ => $crate::types::type_error("expecting (str) arg"),
             }
         }
     }};
@@ -59,141 +66,236 @@ macro_rules! fn_str {
 
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
-        _ => error("illegal symbol call"),
+        Str(ref s) => Ok(Sym(Symbol::intern(s))),
+        _ => type_error("illegal symbol call"),
+    }
+}
+
+// `(get coll key)` or `(get coll key not-found)`.
+// The value at `k` in a map, vector or string, or `None` if there is no
+// such key or index.
+fn lookup(m: &MalVal, k: &MalVal) -> Option<MalVal> {
+    let index = match k {
+        Int(idx) => usize::try_from(*idx).ok(),
+        _ => None,
+    };
+    match m {
+        Hash(hm, _) => hm.get(k).cloned(),
+        Vector(v, _) => index.and_then(|i| v.get(i)).cloned(),
+        Str(s) => index.and_then(|i| s.chars().nth(i)).map(Char),
+        _ => None,
     }
 }
 
 fn get(a: MalArgs) -> MalRet {
+    if !(2..=3).contains(&a.len()) {
+        return arity_error("get: expecting 2 or 3 args");
+    }
     match a[0] {
-        Nil => Ok(Nil),
-        Hash(ref hm, _) => match hm.get(&wrap_map_key(&a[1])?) {
-            Some(mv) => Ok(mv.clone()),
-            None => Ok(Nil),
-        },
-        _ => error("illegal get args"),
+        Nil | Hash(..) | Vector(..) | Str(_) => Ok(lookup(&a[0], &a[1]).or_else(|| a.get(2).cloned()).unwrap_or(Nil)),
+        _ => type_error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
+    if a.is_empty() {
+        return arity_error("assoc: expecting a collection");
+    }
     match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+        Hash(ref hm, _) => _assoc(hm.clone(), a[1..].to_vec()),
+        Nil => _assoc(new_map(), a[1..].to_vec()),
+        Vector(ref v, _) => {
+            if a.len() % 2 != 1 {
+                return arity_error("odd number of elements");
+            }
+            let mut v = v.clone();
+            for (k, x) in a[1..].iter().cloned().tuples() {
+                match k {
+                    Int(i) if usize::try_from(i).is_ok_and(|i| i < v.len()) => {
+                        v.set_mut(i as usize, x);
+                    }
+                    Int(i) if i as usize == v.len() => v.push_back_mut(x),
+                    _ => return error("assoc: index out of range"),
+                }
+            }
+            Ok(Vector(v, Rc::new(Nil)))
+        }
+        _ => type_error("assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => {
-            let mut new_hm = (**hm).clone();
+            let mut new_hm = hm.clone();
             for k in a[1..].iter() {
-                let _ = new_hm.remove(&wrap_map_key(k)?);
+                new_hm.remove_mut(k);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(Hash(new_hm, Rc::new(Nil)))
         }
-        _ => error("dissoc on non-Hash Map"),
+        _ => type_error("dissoc on non-Hash Map"),
     }
 }
 
 fn contains_q(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(Bool(hm.contains_key(&wrap_map_key(&a[1])?))),
-        _ => error("illegal get args"),
+        Hash(ref hm, _) => Ok(Bool(hm.contains_key(&a[1]))),
+        Set(ref s, _) => Ok(Bool(s.contains(&a[1]))),
+        _ => type_error("illegal get args"),
+    }
+}
+
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref s, _) => {
+            let mut new_s = s.clone();
+            for k in a[1..].iter() {
+                new_s.remove_mut(k);
+            }
+            Ok(Set(new_s, Rc::new(Nil)))
+        }
+        _ => type_error("disj on non-Set"),
+    }
+}
+
+fn set(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(_, _) => Ok(a[0].clone()),
+        Nil => Ok(Set(new_set(), Rc::new(Nil))),
+        _ => match a[0].as_seq() {
+            Some(v) => Ok(Set(v.iter().cloned().collect(), Rc::new(Nil))),
+            None => type_error("non-seq passed to set"),
+        },
+    }
+}
+
+// Collects the set arguments of `union`, `intersection` and `difference`.
+fn sets<'a>(name: &str, a: &'a MalArgs) -> Result<Vec<&'a MalSet>, MalErr> {
+    a.iter()
+        .map(|x| match x {
+            Set(s, _) => Ok(s),
+            _ => Err(MalErr::Type(format!("{}: expecting set args", name))),
+        })
+        .collect()
+}
+
+fn union(a: MalArgs) -> MalRet {
+    let mut res = new_set();
+    for s in sets("union", &a)? {
+        for x in s.iter() {
+            res.insert_mut(x.clone());
+        }
     }
+    Ok(Set(res, Rc::new(Nil)))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+    let sets = sets("intersection", &a)?;
+    let Some((first, others)) = sets.split_first() else {
+        return arity_error("intersection: expecting at least 1 arg");
+    };
+    let res = first.iter().filter(|x| others.iter().all(|s| s.contains(*x))).cloned().collect();
+    Ok(Set(res, Rc::new(Nil)))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+    let sets = sets("difference", &a)?;
+    let Some((first, others)) = sets.split_first() else {
+        return arity_error("difference: expecting at least 1 arg");
+    };
+    let res = first.iter().filter(|x| !others.iter().any(|s| s.contains(*x))).cloned().collect();
+    Ok(Set(res, Rc::new(Nil)))
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list(hm.keys().map(|k| unwrap_map_key(k)).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list(hm.keys().cloned().collect())),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => Ok(list(hm.values().cloned().collect())),
-        _ => error("vals requires Hash Map"),
+        _ => type_error("vals requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) => Ok(Vector(v.clone(), Rc::new(Nil))),
+        List(ref v, _) => Ok(Vector(v.iter().cloned().collect(), Rc::new(Nil))),
         Vector(_, _) => Ok(a[0].clone()),
-        _ => error("non-seq passed to vec"),
+        _ => type_error("non-seq passed to vec"),
     }
 }
 
 fn cons(a: MalArgs) -> MalRet {
-    match &a[1] {
-        List(v, _) | Vector(v, _) => {
+    match a[1].as_seq() {
+        Some(v) => {
             let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(v);
+            new_v.extend_from_slice(&v);
             Ok(list(new_v))
         }
-        _ => error("cons expects seq as second arg"),
+        None => type_error("cons expects seq as second arg"),
     }
 }
 
 fn concat(a: MalArgs) -> MalRet {
     let mut new_v = vec![];
     for seq in a.iter() {
-        match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+        match seq.as_seq() {
+            Some(v) => new_v.extend_from_slice(&v),
+            None => return type_error("non-seq passed to concat"),
         }
     }
     Ok(list(new_v))
 }
 
 fn nth(a: MalArgs) -> MalRet {
-    match (&a[0], &a[1]) {
-        (List(seq, _) | Vector(seq, _), Int(idx)) => match seq.get(*idx as usize) {
-            Some(result) => Ok(result.clone()),
-            None => error("nth: index out of range"),
-        },
-        _ => error("invalid args to nth"),
+    let item = match (&a[0], &a[1]) {
+        (List(seq, _), Int(idx)) => seq.get(*idx as usize),
+        (Vector(seq, _), Int(idx)) => seq.get(*idx as usize),
+        (Str(s), Int(idx)) => {
+            return match s.chars().nth(*idx as usize) {
+                Some(c) => Ok(Char(c)),
+                None => error("nth: index out of range"),
+            };
+        }
+        _ => return type_error("invalid args to nth"),
+    };
+    match item {
+        Some(result) => Ok(result.clone()),
+        None => error("nth: index out of range"),
     }
 }
 
 fn first(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() > 0 => Ok(seq[0].clone()),
-        List(_, _) | Vector(_, _) | Nil => Ok(Nil),
-        _ => error("invalid args to first"),
+        List(ref seq, _) if seq.len() > 0 => Ok(seq[0].clone()),
+        Vector(ref seq, _) => Ok(seq.first().cloned().unwrap_or(Nil)),
+        List(_, _) | Nil => Ok(Nil),
+        _ => type_error("invalid args to first"),
     }
 }
 
 fn rest(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() > 1 => Ok(list(seq[1..].to_vec())),
+        List(ref seq, _) if seq.len() > 1 => Ok(list(seq[1..].to_vec())),
+        Vector(ref seq, _) if seq.len() > 1 => Ok(list(seq.iter().skip(1).cloned().collect())),
         List(_, _) | Vector(_, _) | Nil => Ok(list!()),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
     }
 }
 
 fn apply(a: MalArgs) -> MalRet {
-    match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
+    match a[a.len() - 1].as_seq() {
+        Some(v) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(v);
+            fargs.extend_from_slice(&v);
             f.apply(fargs)
         }
-        _ => error("apply called with non-seq"),
-    }
-}
-
-fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
-            }
-            Ok(list(res))
-        }
-        _ => error("map called with non-seq"),
+        None => type_error("apply called with non-seq"),
     }
 }
 
@@ -203,42 +305,250 @@ fn conj(a: MalArgs) -> MalRet {
             let sl = a[1..].iter().rev().cloned().collect::<Vec<MalVal>>();
             Ok(list([&sl[..], v].concat()))
         }
-        Vector(ref v, _) => Ok(vector([v, &a[1..]].concat())),
-        _ => error("conj: called with non-seq"),
+        Vector(ref v, _) => {
+            let mut v = v.clone();
+            for x in a[1..].iter() {
+                v.push_back_mut(x.clone());
+            }
+            Ok(Vector(v, Rc::new(Nil)))
+        }
+        Set(ref s, _) => {
+            let mut s = s.clone();
+            for x in a[1..].iter() {
+                s.insert_mut(x.clone());
+            }
+            Ok(Set(s, Rc::new(Nil)))
+        }
+        // Entries are `[key value]` pairs or whole maps.
+        Hash(ref hm, _) => {
+            let mut hm = hm.clone();
+            for x in a[1..].iter() {
+                match x {
+                    Hash(other, _) => other.iter().for_each(|(k, v)| hm.insert_mut(k.clone(), v.clone())),
+                    _ => match x.as_seq() {
+                        Some(kv) if kv.len() == 2 => hm.insert_mut(kv[0].clone(), kv[1].clone()),
+                        _ => return type_error("conj: map entries must be [key value] pairs"),
+                    },
+                }
+            }
+            Ok(Hash(hm, Rc::new(Nil)))
+        }
+        Nil => Ok(list(a[1..].iter().rev().cloned().collect())),
+        _ => type_error("conj: called with non-seq"),
     }
 }
 
 fn seq(a: MalArgs) -> MalRet {
     match a[0] {
         ref l @ List(ref v, _) if v.len() > 0 => Ok(l.clone()),
-        Vector(ref v, _) if v.len() > 0 => Ok(list(v.to_vec())),
-        Str(ref s) if !s.is_empty() => Ok(list(s.chars().map(|c| Str(c.to_string())).collect())),
-        List(_, _) | Vector(_, _) | Str(_) | Nil => Ok(Nil),
-        _ => error("seq: called with non-seq"),
+        Vector(ref v, _) if !v.is_empty() => Ok(list(v.iter().cloned().collect())),
+        Set(ref s, _) if !s.is_empty() => Ok(list(s.iter().cloned().collect())),
+        Str(ref s) if !s.is_empty() => Ok(list(s.chars().map(Char).collect())),
+        Hash(ref hm, _) if !hm.is_empty() => Ok(list(items(&a[0], "seq")?)),
+        List(_, _) | Vector(_, _) | Set(_, _) | Str(_) | Hash(_, _) | Nil => Ok(Nil),
+        _ => type_error("seq: called with non-seq"),
+    }
+}
+
+fn int_to_char(a: MalArgs) -> MalRet {
+    match a[0] {
+        Char(_) => Ok(a[0].clone()),
+        Int(i) => match u32::try_from(i).ok().and_then(char::from_u32) {
+            Some(c) => Ok(Char(c)),
+            None => error("int->char: not a Unicode code point"),
+        },
+        _ => type_error("int->char: expecting an int"),
+    }
+}
+
+// Maps a character through `f`, keeping it as is when `f` would turn it
+// into several characters (as upper-casing `ß` would).
+fn map_char<I: Iterator<Item = char>>(a: MalArgs, f: fn(char) -> I) -> MalRet {
+    match a[0] {
+        Char(c) => {
+            let mut mapped = f(c);
+            match (mapped.next(), mapped.next()) {
+                (Some(m), None) => Ok(Char(m)),
+                _ => Ok(Char(c)),
+            }
+        }
+        _ => type_error("expecting a char"),
+    }
+}
+
+fn char_is(a: MalArgs, f: fn(char) -> bool) -> MalRet {
+    match a[0] {
+        Char(c) => Ok(Bool(f(c))),
+        _ => type_error("expecting a char"),
     }
 }
 
 fn keyword(a: MalArgs) -> MalRet {
     match a[0] {
         Kwd(_) => Ok(a[0].clone()),
-        Str(ref s) => Ok(Kwd(String::from(s))),
-        _ => error("invalid type for keyword"),
+        Str(ref s) => Ok(Kwd(Symbol::intern(s))),
+        _ => type_error("invalid type for keyword"),
     }
 }
 
+// string operations
+//
+// Indices count characters, not bytes, as `nth` on strings does.
+
+fn str_arg<'a>(a: &'a MalArgs, i: usize, name: &str) -> Result<&'a str, MalErr> {
+    match a.get(i) {
+        Some(Str(s)) => Ok(s),
+        _ => Err(MalErr::Type(format!("{}: expecting a string", name))),
+    }
+}
+
+// A string or character to search for.
+fn text_arg(a: &MalArgs, i: usize, name: &str) -> Result<String, MalErr> {
+    match a.get(i) {
+        Some(Str(s)) => Ok(s.clone()),
+        Some(Char(c)) => Ok(c.to_string()),
+        _ => Err(MalErr::Type(format!("{}: expecting a string or char", name))),
+    }
+}
+
+// Character index `i` of `s` as a byte offset, or `None` past the end.
+fn byte_offset(s: &str, i: &MalVal) -> Option<usize> {
+    let i = match i {
+        Int(i) => usize::try_from(*i).ok()?,
+        _ => return None,
+    };
+    s.char_indices().map(|(b, _)| b).chain(Some(s.len())).nth(i)
+}
+
+fn char_index(s: &str, byte: usize) -> MalVal {
+    Int(s[..byte].chars().count() as i64)
+}
+
+fn subs(a: MalArgs) -> MalRet {
+    if !(2..=3).contains(&a.len()) {
+        return arity_error("subs: expecting 2 or 3 args");
+    }
+    let s = str_arg(&a, 0, "subs")?;
+    let start = byte_offset(s, &a[1]);
+    let end = match a.get(2) {
+        Some(end) => byte_offset(s, end),
+        None => Some(s.len()),
+    };
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => Ok(Str(s[start..end].to_string())),
+        _ => error("subs: index out of range"),
+    }
+}
+
+fn string_to_number(a: MalArgs) -> MalRet {
+    match parse_number(str_arg(&a, 0, "string->number")?.trim()) {
+        Some(Ok(n)) => Ok(n),
+        _ => Ok(Nil),
+    }
+}
+
+fn split(a: MalArgs) -> MalRet {
+    if a.len() != 2 {
+        return arity_error("str/split: expecting exactly 2 args");
+    }
+    let s = str_arg(&a, 0, "str/split")?;
+    #[cfg(feature = "regex")]
+    if let MalVal::Regex(re) = &a[1] {
+        return Ok(re.split(s));
+    }
+    let sep = text_arg(&a, 1, "str/split")?;
+    let parts: Vec<MalVal> = if sep.is_empty() {
+        s.chars().map(|c| Str(c.to_string())).collect()
+    } else {
+        s.split(&sep[..]).map(|p| Str(p.to_string())).collect()
+    };
+    Ok(vector(parts))
+}
+
+fn join(a: MalArgs) -> MalRet {
+    let (sep, coll) = match a.len() {
+        1 => (String::new(), &a[0]),
+        _ => (text_arg(&a, 0, "str/join")?, &a[1]),
+    };
+    let items = match coll {
+        Set(s, _) => s.iter().cloned().collect(),
+        Nil => vec![],
+        _ => match coll.as_seq() {
+            Some(v) => v.into_owned(),
+            None => return type_error("str/join: expecting a collection"),
+        },
+    };
+    Ok(Str(pr_seq(&items, false, "", "", &sep)))
+}
+
+fn capitalize(s: &str) -> MalRet {
+    let mut chars = s.chars();
+    Ok(Str(match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars.as_str().to_lowercase().chars()).collect(),
+        None => String::new(),
+    }))
+}
+
+fn index_of(a: MalArgs) -> MalRet {
+    let s = str_arg(&a, 0, "str/index-of")?;
+    let x = text_arg(&a, 1, "str/index-of")?;
+    let from = match a.get(2) {
+        Some(from) => match byte_offset(s, from) {
+            Some(b) => b,
+            None => return Ok(Nil),
+        },
+        None => 0,
+    };
+    Ok(s[from..].find(&x[..]).map_or(Nil, |b| char_index(s, from + b)))
+}
+
+fn last_index_of(a: MalArgs) -> MalRet {
+    let s = str_arg(&a, 0, "str/last-index-of")?;
+    let x = text_arg(&a, 1, "str/last-index-of")?;
+    Ok(s.rfind(&x[..]).map_or(Nil, |b| char_index(s, b)))
+}
+
+fn replace(a: MalArgs) -> MalRet {
+    if a.len() != 3 {
+        return arity_error("str/replace: expecting exactly 3 args");
+    }
+    let s = str_arg(&a, 0, "str/replace")?;
+    #[cfg(feature = "regex")]
+    if let MalVal::Regex(re) = &a[1] {
+        return re.replace(s, &a[2]);
+    }
+    let from = text_arg(&a, 1, "str/replace")?;
+    let to = text_arg(&a, 2, "str/replace")?;
+    Ok(Str(s.replace(&from[..], &to)))
+}
+
+// `str/starts-with?`, `str/ends-with?` and `str/includes?`.
+fn str_test(a: MalArgs, name: &str, f: fn(&str, &str) -> bool) -> MalRet {
+    let s = str_arg(&a, 0, name)?;
+    Ok(Bool(f(s, &text_arg(&a, 1, name)?)))
+}
+
 pub fn empty_q(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref l, _) | Vector(ref l, _) => Ok(Bool(l.len() == 0)),
+        List(ref l, _) => Ok(Bool(l.len() == 0)),
+        Vector(ref v, _) => Ok(Bool(v.is_empty())),
+        Set(ref s, _) => Ok(Bool(s.is_empty())),
+        Hash(ref hm, _) => Ok(Bool(hm.is_empty())),
+        Str(ref s) => Ok(Bool(s.is_empty())),
         Nil => Ok(Bool(true)),
-        _ => error("invalid type for empty?"),
+        _ => type_error("invalid type for empty?"),
     }
 }
 
 pub fn count(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref l, _) | Vector(ref l, _) => Ok(Int(l.len() as i64)),
+        List(ref l, _) => Ok(Int(l.len() as i64)),
+        Vector(ref v, _) => Ok(Int(v.len() as i64)),
+        Set(ref s, _) => Ok(Int(s.size() as i64)),
+        Hash(ref hm, _) => Ok(Int(hm.size() as i64)),
+        Str(ref s) => Ok(Int(s.chars().count() as i64)),
         Nil => Ok(Int(0)),
-        _ => error("invalid type for count"),
+        _ => type_error("invalid type for count"),
     }
 }
 
@@ -249,7 +559,7 @@ pub fn atom(a: MalArgs) -> MalRet {
 pub fn deref(a: MalArgs) -> MalRet {
     match a[0] {
         Atom(ref a) => Ok(a.borrow().clone()),
-        _ => error("attempt to deref a non-Atom"),
+        _ => type_error("attempt to deref a non-Atom"),
     }
 }
 
@@ -259,7 +569,7 @@ pub fn reset_bang(a: MalArgs) -> MalRet {
             *atm.borrow_mut() = a[1].clone();
             Ok(a[1].clone())
         }
-        _ => error("attempt to reset! a non-Atom"),
+        _ => type_error("attempt to reset! a non-Atom"),
     }
 }
 
@@ -272,17 +582,17 @@ pub fn swap_bang(a: MalArgs) -> MalRet {
             *atm.borrow_mut() = result.clone();
             Ok(result)
         }
-        _ => error("attempt to swap! a non-Atom"),
+        _ => type_error("attempt to swap! a non-Atom"),
     }
 }
 
 pub fn get_meta(a: MalArgs) -> MalRet {
     match a[0] {
-        List(_, ref meta) | Vector(_, ref meta) | Hash(_, ref meta) => Ok((**meta).clone()),
+        List(_, ref meta) | Vector(_, ref meta) | Hash(_, ref meta) | Set(_, ref meta) => Ok((**meta).clone()),
         Func(_, ref meta) => Ok((**meta).clone()),
         NativeClosure(_, ref meta) => Ok((**meta).clone()),
         MalFunc(FuncStruct { ref meta, .. }) => Ok((**meta).clone()),
-        _ => error("meta not supported by type"),
+        _ => type_error("meta not supported by type"),
     }
 }
 
@@ -292,31 +602,554 @@ pub fn with_meta(a: MalArgs) -> MalRet {
         List(ref l, _) => Ok(List(l.clone(), m)),
         Vector(ref l, _) => Ok(Vector(l.clone(), m)),
         Hash(ref l, _) => Ok(Hash(l.clone(), m)),
+        Set(ref l, _) => Ok(Set(l.clone(), m)),
         Func(ref l, _) => Ok(Func(*l, m)),
         NativeClosure(ref f, _) => Ok(NativeClosure(f.clone(), m)),
         MalFunc(ref f @ FuncStruct { .. }) => Ok(MalFunc(FuncStruct {
             meta: m,
             ..f.clone()
         })),
-        _ => error("with-meta not supported by type"),
+        _ => type_error("with-meta not supported by type"),
+    }
+}
+
+// sequence library
+//
+// These accept any collection: lists, vectors, sets, strings (as chars),
+// maps (as `[key value]` vectors) and nil (as empty), and return lists.
+
+fn items(coll: &MalVal, name: &str) -> Result<Vec<MalVal>, MalErr> {
+    match coll {
+        List(l, _) => Ok(l.to_vec()),
+        Vector(v, _) => Ok(v.iter().cloned().collect()),
+        Set(s, _) => Ok(s.iter().cloned().collect()),
+        Hash(hm, _) => Ok(hm.iter().map(|(k, v)| vector(vec![k.clone(), v.clone()])).collect()),
+        Str(s) => Ok(s.chars().map(Char).collect()),
+        Nil => Ok(vec![]),
+        _ => Err(MalErr::Type(format!("{}: expecting a collection", name))),
+    }
+}
+
+fn truthy(v: &MalVal) -> bool {
+    !matches!(v, Bool(false) | Nil)
+}
+
+fn arg_count(a: &MalArgs, n: usize, name: &str) -> Result<(), MalErr> {
+    match (a.len() == n, n) {
+        (true, _) => Ok(()),
+        (false, 1) => Err(MalErr::Arity(format!("{}: expecting exactly 1 arg", name))),
+        (false, _) => Err(MalErr::Arity(format!("{}: expecting exactly {} args", name, n))),
+    }
+}
+
+fn count_arg(n: &MalVal, name: &str) -> Result<usize, MalErr> {
+    match n {
+        Int(n) => Ok(usize::try_from(*n).unwrap_or(0)),
+        _ => Err(MalErr::Type(format!("{}: expecting an int", name))),
+    }
+}
+
+fn map(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    if a.len() < 2 {
+        return arity_error("map: expecting a function and at least 1 collection");
+    }
+    let colls = a[1..].iter().map(|c| items(c, "map")).collect::<Result<Vec<_>, _>>()?;
+    let len = colls.iter().map(Vec::len).min().unwrap_or(0);
+    let mut res = vec![];
+    for i in 0..len {
+        interrupt.check(i)?;
+        res.push(a[0].apply(colls.iter().map(|c| c[i].clone()).collect())?);
+    }
+    Ok(list(res))
+}
+
+fn mapcat(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    let mut res = vec![];
+    for part in items(&map(interrupt, a)?, "mapcat")? {
+        res.extend(items(&part, "mapcat")?);
+    }
+    Ok(list(res))
+}
+
+// `filter` keeps the items `pred` holds for, `remove` the others.
+fn filter_by(interrupt: &Interrupt, a: MalArgs, keep: bool, name: &str) -> MalRet {
+    arg_count(&a, 2, name)?;
+    let mut res = vec![];
+    for (i, x) in items(&a[1], name)?.into_iter().enumerate() {
+        interrupt.check(i)?;
+        if truthy(&a[0].apply(vec![x.clone()])?) == keep {
+            res.push(x);
+        }
+    }
+    Ok(list(res))
+}
+
+fn reduce(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    let (init, coll) = match a.len() {
+        2 => (None, items(&a[1], "reduce")?),
+        3 => (Some(a[1].clone()), items(&a[2], "reduce")?),
+        _ => return arity_error("reduce: expecting 2 or 3 args"),
+    };
+    let mut coll = coll.into_iter();
+    let mut acc = match init.or_else(|| coll.next()) {
+        Some(acc) => acc,
+        None => return a[0].apply(vec![]),
+    };
+    for (i, x) in coll.enumerate() {
+        interrupt.check(i)?;
+        acc = a[0].apply(vec![acc, x])?;
+    }
+    Ok(acc)
+}
+
+fn range(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    let (start, end, step) = match a.len() {
+        1 => (Int(0), a[0].clone(), Int(1)),
+        2 => (a[0].clone(), a[1].clone(), Int(1)),
+        3 => (a[0].clone(), a[1].clone(), a[2].clone()),
+        _ => return arity_error("range: expecting 1 to 3 args"),
+    };
+    let rising = match compare_numbers(&step, &Int(0))? {
+        Some(Ordering::Greater) => Ordering::Less,
+        Some(Ordering::Less) => Ordering::Greater,
+        _ => return error("range: step must not be zero"),
+    };
+    let mut res = vec![];
+    if let (Int(start), Int(end), Int(step)) = (&start, &end, &step) {
+        let mut x = *start;
+        while x.cmp(end) == rising {
+            interrupt.check(res.len())?;
+            res.push(Int(x));
+            match x.checked_add(*step) {
+                Some(next) => x = next,
+                None => break,
+            }
+        }
+        return Ok(list(res));
+    }
+    let mut x = start;
+    while compare_numbers(&x, &end)? == Some(rising) {
+        interrupt.check(res.len())?;
+        res.push(x.clone());
+        x = op2(i64::checked_add, |x, y| x + y, |x, y| x + y, &x, &step)?;
+    }
+    Ok(list(res))
+}
+
+fn take(a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "take")?;
+    let n = count_arg(&a[0], "take")?;
+    Ok(list(items(&a[1], "take")?.into_iter().take(n).collect()))
+}
+
+fn drop_first(a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "drop")?;
+    let n = count_arg(&a[0], "drop")?;
+    Ok(list(items(&a[1], "drop")?.into_iter().skip(n).collect()))
+}
+
+// Number of leading items of `coll` that `pred` holds for.
+fn prefix_len(interrupt: &Interrupt, pred: &MalVal, coll: &[MalVal]) -> Result<usize, MalErr> {
+    for (i, x) in coll.iter().enumerate() {
+        interrupt.check(i)?;
+        if !truthy(&pred.apply(vec![x.clone()])?) {
+            return Ok(i);
+        }
+    }
+    Ok(coll.len())
+}
+
+fn take_while(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "take-while")?;
+    let mut coll = items(&a[1], "take-while")?;
+    coll.truncate(prefix_len(interrupt, &a[0], &coll)?);
+    Ok(list(coll))
+}
+
+fn drop_while(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "drop-while")?;
+    let coll = items(&a[1], "drop-while")?;
+    let n = prefix_len(interrupt, &a[0], &coll)?;
+    Ok(list(coll[n..].to_vec()))
+}
+
+// `(partition n coll)` or `(partition n step coll)`; a last partition
+// shorter than `n` is dropped.
+fn partition(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    let (n, step, coll) = match a.len() {
+        2 => {
+            let n = count_arg(&a[0], "partition")?;
+            (n, n, items(&a[1], "partition")?)
+        }
+        3 => (count_arg(&a[0], "partition")?, count_arg(&a[1], "partition")?, items(&a[2], "partition")?),
+        _ => return arity_error("partition: expecting 2 or 3 args"),
+    };
+    if n == 0 || step == 0 {
+        return error("partition: size and step must be positive");
+    }
+    let mut res = vec![];
+    for i in (0..coll.len()).step_by(step).take_while(|i| i + n <= coll.len()) {
+        interrupt.check(res.len())?;
+        res.push(list(coll[i..i + n].to_vec()));
     }
+    Ok(list(res))
+}
+
+fn interleave(a: MalArgs) -> MalRet {
+    let colls = a.iter().map(|c| items(c, "interleave")).collect::<Result<Vec<_>, _>>()?;
+    let len = colls.iter().map(Vec::len).min().unwrap_or(0);
+    Ok(list((0..len).flat_map(|i| colls.iter().map(move |c| c[i].clone())).collect()))
+}
+
+fn reverse(a: MalArgs) -> MalRet {
+    arg_count(&a, 1, "reverse")?;
+    Ok(list(items(&a[0], "reverse")?.into_iter().rev().collect()))
+}
+
+// Orders numbers, strings, chars, keywords, symbols, booleans and
+// sequences of them element by element; nil comes before everything.
+fn mal_compare(x: &MalVal, y: &MalVal) -> Result<Ordering, MalErr> {
+    match (x, y) {
+        (Nil, Nil) => Ok(Ordering::Equal),
+        (Nil, _) => Ok(Ordering::Less),
+        (_, Nil) => Ok(Ordering::Greater),
+        (Str(x), Str(y)) => Ok(x.cmp(y)),
+        (Char(x), Char(y)) => Ok(x.cmp(y)),
+        (Kwd(x), Kwd(y)) | (Sym(x), Sym(y)) => Ok(x.as_str().cmp(y.as_str())),
+        (Bool(x), Bool(y)) => Ok(x.cmp(y)),
+        (List(..) | Vector(..), List(..) | Vector(..)) => {
+            let (x, y) = (x.as_seq().unwrap(), y.as_seq().unwrap());
+            for (a, b) in x.iter().zip(y.iter()) {
+                match mal_compare(a, b)? {
+                    Ordering::Equal => {}
+                    o => return Ok(o),
+                }
+            }
+            Ok(x.len().cmp(&y.len()))
+        }
+        _ => match compare_numbers(x, y) {
+            Ok(o) => Ok(o.unwrap_or(Ordering::Equal)),
+            Err(_) => type_error("compare: cannot compare these values"),
+        },
+    }
+}
+
+// Calls a user comparator, which returns a number like `compare` or, like
+// `<`, a boolean saying whether `x` comes first.
+fn call_comparator(f: &MalVal, x: &MalVal, y: &MalVal) -> Result<Ordering, MalErr> {
+    match f.apply(vec![x.clone(), y.clone()])? {
+        Bool(true) => Ok(Ordering::Less),
+        Bool(false) | Nil => match f.apply(vec![y.clone(), x.clone()])? {
+            Bool(true) => Ok(Ordering::Greater),
+            _ => Ok(Ordering::Equal),
+        },
+        n => mal_compare(&n, &Int(0)),
+    }
+}
+
+// Stable merge sort that stops at the first comparison error. (`sort_by`
+// may panic when a user comparator is not a total order.)
+fn merge_sort(
+    v: Vec<MalVal>,
+    cmp: &mut dyn FnMut(&MalVal, &MalVal) -> Result<Ordering, MalErr>,
+) -> Result<Vec<MalVal>, MalErr> {
+    if v.len() <= 1 {
+        return Ok(v);
+    }
+    let mut left = v;
+    let right = left.split_off(left.len() / 2);
+    let (left, right) = (merge_sort(left, cmp)?, merge_sort(right, cmp)?);
+    let mut res = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(x), Some(y)) = (left.peek(), right.peek()) {
+        match cmp(y, x)? {
+            Ordering::Less => res.push(right.next().unwrap()),
+            _ => res.push(left.next().unwrap()),
+        }
+    }
+    res.extend(left);
+    res.extend(right);
+    Ok(res)
+}
+
+fn sort(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    let (cmp, coll) = match a.len() {
+        1 => (None, &a[0]),
+        2 => (Some(&a[0]), &a[1]),
+        _ => return arity_error("sort: expecting 1 or 2 args"),
+    };
+    let mut compared = 0;
+    let mut by = |x: &MalVal, y: &MalVal| {
+        interrupt.check(compared)?;
+        compared += 1;
+        match cmp {
+            Some(f) => call_comparator(f, x, y),
+            None => mal_compare(x, y),
+        }
+    };
+    Ok(list(merge_sort(items(coll, "sort")?, &mut by)?))
+}
+
+fn sort_by(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    let (keyfn, cmp, coll) = match a.len() {
+        2 => (&a[0], None, &a[1]),
+        3 => (&a[0], Some(&a[1]), &a[2]),
+        _ => return arity_error("sort-by: expecting 2 or 3 args"),
+    };
+    // Sorts `[key item]` pairs so that each key is computed once.
+    let keyed = items(coll, "sort-by")?
+        .into_iter()
+        .map(|x| Ok(vector(vec![keyfn.apply(vec![x.clone()])?, x])))
+        .collect::<Result<Vec<_>, MalErr>>()?;
+    let key = |v: &MalVal| match v {
+        Vector(v, _) => v[0].clone(),
+        _ => unreachable!(),
+    };
+    let mut compared = 0;
+    let mut by = |x: &MalVal, y: &MalVal| {
+        interrupt.check(compared)?;
+        compared += 1;
+        match cmp {
+            Some(f) => call_comparator(f, &key(x), &key(y)),
+            None => mal_compare(&key(x), &key(y)),
+        }
+    };
+    let sorted = merge_sort(keyed, &mut by)?;
+    Ok(list(sorted.iter().map(|v| v.as_seq().unwrap()[1].clone()).collect()))
+}
+
+fn group_by(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "group-by")?;
+    let mut groups = new_map();
+    for (i, x) in items(&a[1], "group-by")?.into_iter().enumerate() {
+        interrupt.check(i)?;
+        let k = a[0].apply(vec![x.clone()])?;
+        let group = match groups.get(&k) {
+            Some(Vector(v, _)) => v.push_back(x),
+            _ => MalVector::new().push_back(x),
+        };
+        groups.insert_mut(k, Vector(group, Rc::new(Nil)));
+    }
+    Ok(Hash(groups, Rc::new(Nil)))
+}
+
+fn frequencies(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 1, "frequencies")?;
+    let mut counts = new_map();
+    for (i, x) in items(&a[0], "frequencies")?.into_iter().enumerate() {
+        interrupt.check(i)?;
+        let n = match counts.get(&x) {
+            Some(Int(n)) => n + 1,
+            _ => 1,
+        };
+        counts.insert_mut(x, Int(n));
+    }
+    Ok(Hash(counts, Rc::new(Nil)))
+}
+
+fn some(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "some")?;
+    for (i, x) in items(&a[1], "some")?.into_iter().enumerate() {
+        interrupt.check(i)?;
+        let res = a[0].apply(vec![x])?;
+        if truthy(&res) {
+            return Ok(res);
+        }
+    }
+    Ok(Nil)
+}
+
+fn every_q(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "every?")?;
+    for (i, x) in items(&a[1], "every?")?.into_iter().enumerate() {
+        interrupt.check(i)?;
+        if !truthy(&a[0].apply(vec![x])?) {
+            return Ok(Bool(false));
+        }
+    }
+    Ok(Bool(true))
+}
+
+fn distinct(interrupt: &Interrupt, a: MalArgs) -> MalRet {
+    arg_count(&a, 1, "distinct")?;
+    let mut seen = new_set();
+    let mut res = vec![];
+    for (i, x) in items(&a[0], "distinct")?.into_iter().enumerate() {
+        interrupt.check(i)?;
+        if !seen.contains(&x) {
+            seen.insert_mut(x.clone());
+            res.push(x);
+        }
+    }
+    Ok(list(res))
+}
+
+fn zipmap(a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "zipmap")?;
+    let mut hm = new_map();
+    for (k, v) in items(&a[0], "zipmap")?.into_iter().zip(items(&a[1], "zipmap")?) {
+        hm.insert_mut(k, v);
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
+}
+
+// map library
+//
+// Paths (`ks`) may be any collection; maps nested inside vectors are
+// reached by index, and `nil` along the path acts as an empty map.
+
+fn get_in(a: MalArgs) -> MalRet {
+    if !(2..=3).contains(&a.len()) {
+        return arity_error("get-in: expecting 2 or 3 args");
+    }
+    let mut m = a[0].clone();
+    for k in items(&a[1], "get-in")? {
+        m = match lookup(&m, &k) {
+            Some(v) => v,
+            None => return Ok(a.get(2).cloned().unwrap_or(Nil)),
+        };
+    }
+    Ok(m)
+}
+
+fn assoc_path(m: &MalVal, ks: &[MalVal], v: MalVal) -> MalRet {
+    match ks {
+        [] => Ok(v),
+        [k, rest @ ..] => {
+            let inner = assoc_path(&get(vec![m.clone(), k.clone()])?, rest, v)?;
+            assoc(vec![m.clone(), k.clone(), inner])
+        }
+    }
+}
+
+fn update_path(m: &MalVal, ks: &[MalVal], f: &MalVal, args: &[MalVal]) -> MalRet {
+    match ks {
+        [] => f.apply([core::slice::from_ref(m), args].concat()),
+        [k, rest @ ..] => {
+            let inner = update_path(&get(vec![m.clone(), k.clone()])?, rest, f, args)?;
+            assoc(vec![m.clone(), k.clone(), inner])
+        }
+    }
+}
+
+fn assoc_in(a: MalArgs) -> MalRet {
+    arg_count(&a, 3, "assoc-in")?;
+    assoc_path(&a[0], &items(&a[1], "assoc-in")?, a[2].clone())
+}
+
+fn update(a: MalArgs) -> MalRet {
+    if a.len() < 3 {
+        return arity_error("update: expecting a map, a key and a function");
+    }
+    update_path(&a[0], &a[1..2], &a[2], &a[3..])
+}
+
+fn update_in(a: MalArgs) -> MalRet {
+    if a.len() < 3 {
+        return arity_error("update-in: expecting a map, a path and a function");
+    }
+    update_path(&a[0], &items(&a[1], "update-in")?, &a[2], &a[3..])
+}
+
+// Merges maps left to right; on a key already present, `f` (if any) is
+// called with the old and new values to combine them.
+fn merge_maps(f: Option<&MalVal>, maps: &[MalVal]) -> MalRet {
+    let mut res: Option<MalMap> = None;
+    for m in maps {
+        let hm = match m {
+            Hash(hm, _) => hm,
+            Nil => continue,
+            _ => return type_error("merge: expecting maps"),
+        };
+        let Some(acc) = res.as_mut() else {
+            res = Some(hm.clone());
+            continue;
+        };
+        for (k, v) in hm.iter() {
+            let v = match (f, acc.get(k)) {
+                (Some(f), Some(old)) => f.apply(vec![old.clone(), v.clone()])?,
+                _ => v.clone(),
+            };
+            acc.insert_mut(k.clone(), v);
+        }
+    }
+    Ok(res.map_or(Nil, |hm| Hash(hm, Rc::new(Nil))))
+}
+
+fn select_keys(a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "select-keys")?;
+    let mut res = new_map();
+    match a[0] {
+        Hash(ref hm, _) => {
+            for k in items(&a[1], "select-keys")? {
+                if let Some(v) = hm.get(&k) {
+                    res.insert_mut(k, v.clone());
+                }
+            }
+        }
+        Nil => {}
+        _ => return type_error("select-keys: expecting a map"),
+    }
+    Ok(Hash(res, Rc::new(Nil)))
+}
+
+fn find(a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "find")?;
+    match a[0] {
+        Hash(ref hm, _) => Ok(hm.get(&a[1]).map_or(Nil, |v| vector(vec![a[1].clone(), v.clone()]))),
+        Nil => Ok(Nil),
+        _ => type_error("find: expecting a map"),
+    }
+}
+
+fn into(a: MalArgs) -> MalRet {
+    arg_count(&a, 2, "into")?;
+    let mut args = vec![a[0].clone()];
+    args.extend(items(&a[1], "into")?);
+    conj(args)
+}
+
+// A native that charges a step per item it loops over, polling `interrupt`.
+fn polled(interrupt: &Interrupt, f: fn(&Interrupt, MalArgs) -> MalRet) -> MalVal {
+    let interrupt = interrupt.clone();
+    func_closure(move |a| f(&interrupt, a))
 }
 
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(a[0].clone()))),
+    ns_with_interrupt(&Interrupt::none())
+}
+
+/// Like `ns`, with the sequence functions polling `interrupt`.
+pub fn ns_with_interrupt(interrupt: &Interrupt) -> Vec<(&'static str, MalVal)> {
+    let ns = vec![
+        ("=", func(|a| Ok(Bool(a[0].equals(&a[1]))))),
+        ("throw", func(|a| Err(MalErr::Throw(a[0].clone())))),
         ("nil?", func(fn_is_type!(Nil))),
         ("true?", func(fn_is_type!(Bool(true)))),
         ("false?", func(fn_is_type!(Bool(false)))),
         ("symbol", func(symbol)),
         ("symbol?", func(fn_is_type!(Sym(_)))),
         ("string?", func(fn_is_type!(Str(_)))),
+        ("char?", func(fn_is_type!(Char(_)))),
+        ("char", func(int_to_char)),
+        ("int->char", func(int_to_char)),
+        ("char->int", func(|a| match a[0] {
+            Char(c) => Ok(Int(c as i64)),
+            _ => type_error("char->int: expecting a char"),
+        })),
+        ("char-upper", func(|a| map_char(a, char::to_uppercase))),
+        ("char-lower", func(|a| map_char(a, char::to_lowercase))),
+        ("char-alphabetic?", func(|a| char_is(a, char::is_alphabetic))),
+        ("char-numeric?", func(|a| char_is(a, char::is_numeric))),
+        ("char-whitespace?", func(|a| char_is(a, char::is_whitespace))),
         ("keyword", func(keyword)),
         ("keyword?", func(fn_is_type!(Kwd(_)))),
-        ("number?", func(fn_is_type!(Int(_) | Float(_)))),
-        ("int?", func(fn_is_type!(Int(_)))),
+        ("number?", func(fn_is_type!(Int(_) | BigInt(_) | Ratio(_) | Float(_)))),
+        ("int?", func(fn_is_type!(Int(_) | BigInt(_)))),
         ("float?", func(fn_is_type!(Float(_)))),
+        ("ratio?", func(fn_is_type!(Ratio(_)))),
+        ("numerator", func(numerator)),
+        ("denominator", func(denominator)),
         (
             "fn?",
             func(fn_is_type!(
@@ -335,6 +1168,29 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
         ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
         ("read-string", func(fn_str!(read_str))),
+        ("subs", func(subs)),
+        ("string->number", func(string_to_number)),
+        // Prefixed like the rest of the string library; the plain names
+        // match Clojure.
+        ("str/subs", func(subs)),
+        ("str/string->number", func(string_to_number)),
+        ("str/split", func(split)),
+        ("str/split-lines", func(fn_str!(|s: &str| Ok(vector(s.lines().map(|l| Str(l.to_string())).collect()))))),
+        ("str/join", func(join)),
+        ("str/upper-case", func(fn_str!(|s: &str| Ok(Str(s.to_uppercase()))))),
+        ("str/lower-case", func(fn_str!(|s: &str| Ok(Str(s.to_lowercase()))))),
+        ("str/capitalize", func(fn_str!(capitalize))),
+        ("str/trim", func(fn_str!(|s: &str| Ok(Str(s.trim().to_string()))))),
+        ("str/triml", func(fn_str!(|s: &str| Ok(Str(s.trim_start().to_string()))))),
+        ("str/trimr", func(fn_str!(|s: &str| Ok(Str(s.trim_end().to_string()))))),
+        ("str/blank?", func(fn_str!(|s: &str| Ok(Bool(s.trim().is_empty()))))),
+        ("str/reverse", func(fn_str!(|s: &str| Ok(Str(s.chars().rev().collect()))))),
+        ("str/starts-with?", func(|a| str_test(a, "str/starts-with?", |s, x| s.starts_with(x)))),
+        ("str/ends-with?", func(|a| str_test(a, "str/ends-with?", |s, x| s.ends_with(x)))),
+        ("str/includes?", func(|a| str_test(a, "str/includes?", |s, x| s.contains(x)))),
+        ("str/index-of", func(index_of)),
+        ("str/last-index-of", func(last_index_of)),
+        ("str/replace", func(replace)),
         ("<", func(less_than)),
         ("<=", func(less_equal)),
         (">", func(greater_than)),
@@ -345,16 +1201,19 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("/", func(division)),
         ("float", func(|a| {
             match &a[0] {
-                Int(i) => Ok(Float(*i as f32)),
-                Float(f) => Ok(Float(*f)),
-                _ => error("float: invalid argument"),
+                Int(_) | BigInt(_) | Ratio(_) | Float(_) => Ok(Float(as_float(&a[0]).unwrap())),
+                _ => type_error("float: invalid argument"),
             }
         })),
         ("int", func(|a| {
             match &a[0] {
-                Int(i) => Ok(Int(*i)),
-                Float(f) => Ok(Int(*f as i64)),
-                _ => error("int: invalid argument"),
+                Int(_) | BigInt(_) => Ok(a[0].clone()),
+                Ratio(r) => Ok(bigint(r.to_integer())),
+                Float(f) => match MalBigInt::from_f64(float_to_f64(*f)) {
+                    Some(n) => Ok(bigint(n)),
+                    None => type_error("int: invalid argument"),
+                },
+                _ => type_error("int: invalid argument"),
             }
         })),
         ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
@@ -364,10 +1223,29 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("vector?", func(fn_is_type!(Vector(_, _)))),
         ("hash-map", func(hash_map)),
         ("map?", func(fn_is_type!(Hash(_, _)))),
+        ("hash-set", func(|a| Ok(Set(a.into_iter().collect(), Rc::new(Nil))))),
+        ("set", func(set)),
+        ("set?", func(fn_is_type!(Set(_, _)))),
+        ("disj", func(disj)),
+        ("union", func(union)),
+        ("intersection", func(intersection)),
+        ("difference", func(difference)),
         ("assoc", func(assoc)),
         ("dissoc", func(dissoc)),
         ("get", func(get)),
         ("contains?", func(contains_q)),
+        ("get-in", func(get_in)),
+        ("assoc-in", func(assoc_in)),
+        ("update", func(update)),
+        ("update-in", func(update_in)),
+        ("merge", func(|a| merge_maps(None, &a))),
+        ("merge-with", func(|a| match a.split_first() {
+            Some((f, maps)) => merge_maps(Some(f), maps),
+            None => arity_error("merge-with: expecting a function"),
+        })),
+        ("select-keys", func(select_keys)),
+        ("find", func(find)),
+        ("into", func(into)),
         ("keys", func(keys)),
         ("vals", func(vals)),
         ("vec", func(vec)),
@@ -379,7 +1257,31 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("rest", func(rest)),
         ("count", func(count)),
         ("apply", func(apply)),
-        ("map", func(map)),
+        ("map", polled(interrupt, map)),
+        ("mapcat", polled(interrupt, mapcat)),
+        ("filter", polled(interrupt, |i, a| filter_by(i, a, true, "filter"))),
+        ("remove", polled(interrupt, |i, a| filter_by(i, a, false, "remove"))),
+        ("reduce", polled(interrupt, reduce)),
+        ("range", polled(interrupt, range)),
+        ("take", func(take)),
+        ("drop", func(drop_first)),
+        ("take-while", polled(interrupt, take_while)),
+        ("drop-while", polled(interrupt, drop_while)),
+        ("partition", polled(interrupt, partition)),
+        ("interleave", func(interleave)),
+        ("reverse", func(reverse)),
+        ("compare", func(|a| {
+            arg_count(&a, 2, "compare")?;
+            Ok(Int(mal_compare(&a[0], &a[1])? as i64))
+        })),
+        ("sort", polled(interrupt, sort)),
+        ("sort-by", polled(interrupt, sort_by)),
+        ("group-by", polled(interrupt, group_by)),
+        ("frequencies", polled(interrupt, frequencies)),
+        ("some", polled(interrupt, some)),
+        ("every?", polled(interrupt, every_q)),
+        ("distinct", polled(interrupt, distinct)),
+        ("zipmap", func(zipmap)),
         ("conj", func(conj)),
         ("seq", func(seq)),
         ("meta", func(get_meta)),
@@ -389,156 +1291,164 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
         ("deref", func(deref)),
         ("reset!", func(reset_bang)),
         ("swap!", func(swap_bang)),
-    ]
+    ];
+    #[cfg(feature = "regex")]
+    let ns = [ns, crate::regex::ns()].concat();
+    ns
 }
 
 // arithmetic operations over array of ints/floats
 
-fn opArray(unity: i64, op_closure_int: fn(i64, i64) -> i64, op_closure_float: fn(f32, f32) -> f32, a: MalArgs) -> MalRet {
-    if a.len() == 0 {
-        return Ok(Int(unity));
-    }
-
-    let mut result_int: i64;
-    let mut result_float: f32;
-    let mut try_int: bool;
+// Integer or ratio operands widened to `MalRatio`, or `None` if either is
+// not one.
+fn exact_operands(x: &MalVal, y: &MalVal) -> Option<(MalRatio, MalRatio)> {
+    let exact = |v: &MalVal| match v {
+        Int(i) => Some(MalRatio::from_integer((*i).into())),
+        BigInt(n) => Some(MalRatio::from_integer(n.clone())),
+        Ratio(r) => Some(r.clone()),
+        _ => None,
+    };
+    Some((exact(x)?, exact(y)?))
+}
 
-    match &a[0] {
-        Int(i) => {
-            result_int = *i;
-            result_float = 0.0;
-            try_int = true;
-        }
-        Float(f) => {
-            result_int = 0;
-            result_float = *f;
-            try_int = false;
-        },
-        _ => return error("expecting (int/float, int/float, ...) args"),
+fn as_float(v: &MalVal) -> Option<MalFloat> {
+    match v {
+        Int(i) => Some(*i as MalFloat),
+        BigInt(n) => Some(bigint_to_float(n)),
+        Ratio(r) => Some(ratio_to_float(r)),
+        Float(f) => Some(*f),
+        _ => None,
     }
-    if a.len() == 1 {
-        if try_int {
-            return Ok(Int(result_int));
-        } else {
-            return Ok(Float(result_float));
-        }
+}
+
+// `i64` arithmetic is checked: a result that overflows, or a quotient that
+// is not whole, is computed again exactly as a `BigInt` or `Ratio`. Any
+// float operand makes the result a float.
+fn opArray(
+    unity: i64,
+    op_closure_int: fn(i64, i64) -> Option<i64>,
+    op_closure_exact: fn(MalRatio, MalRatio) -> MalRatio,
+    op_closure_float: fn(MalFloat, MalFloat) -> MalFloat,
+    a: MalArgs,
+) -> MalRet {
+    if a.len() == 0 {
+        return Ok(Int(unity));
     }
 
+    let mut result = match &a[0] {
+        Int(_) | BigInt(_) | Ratio(_) | Float(_) => a[0].clone(),
+        _ => return type_error("expecting (int/float, int/float, ...) args"),
+    };
 
     for arg in a[1..].iter() {
-        match arg {
-            // all ints so far:
-            Int(i) if try_int => result_int = op_closure_int(result_int, *i),
-
-            // float encountered previously, switch to float result:
-            Int(i) => result_float = op_closure_float(result_float, *i as f32),
-
-            // first encountered float, switch to float result:
-            Float (f) if try_int=> {
-                result_float = op_closure_float(*f, result_int as f32);
-                // switch to float mode:
-                try_int = false;
-            }
-
-            // already in float mode:
-            Float (f) => result_float = op_closure_float(result_float, *f),
-
-            // error case:
-            _ => return error("expecting (int/float, int/float, ...) args"),
-        }
+        result = op2(op_closure_int, op_closure_exact, op_closure_float, &result, arg)?;
     }
-    if try_int {
-        Ok(Int(result_int))
-    } else {
-        Ok(Float(result_float))
+    Ok(result)
+}
+
+// One step of `opArray`, on two operands.
+fn op2(
+    op_closure_int: fn(i64, i64) -> Option<i64>,
+    op_closure_exact: fn(MalRatio, MalRatio) -> MalRatio,
+    op_closure_float: fn(MalFloat, MalFloat) -> MalFloat,
+    x: &MalVal,
+    y: &MalVal,
+) -> MalRet {
+    match (x, y) {
+        (Int(x), Int(y)) if let Some(r) = op_closure_int(*x, *y) => Ok(Int(r)),
+        (Float(_), _) | (_, Float(_)) => match (as_float(x), as_float(y)) {
+            (Some(x), Some(y)) => Ok(Float(op_closure_float(x, y))),
+            _ => type_error("expecting (int/float, int/float, ...) args"),
+        },
+        _ => match exact_operands(x, y) {
+            Some((x, y)) => Ok(ratio(op_closure_exact(x, y))),
+            None => type_error("expecting (int/float, int/float, ...) args"),
+        },
     }
 }
 
 fn addition(a: MalArgs) -> MalRet {
-    opArray(0, |x:i64, y:i64| {x+y}, |x:f32, y:f32| {x+y}, a)
+    opArray(0, i64::checked_add, |x, y| x + y, |x, y| x + y, a)
 }
 
 fn substraction(a: MalArgs) -> MalRet {
-    opArray(0, |x:i64, y:i64| {x-y}, |x:f32, y:f32| {x-y}, a)
+    // `(- x)` negates `x`.
+    if let [x] = &a[..] {
+        return match x {
+            Float(f) => Ok(Float(-f)),
+            _ => op2(i64::checked_sub, |x, y| x - y, |x, y| x - y, &Int(0), x),
+        };
+    }
+    opArray(0, i64::checked_sub, |x, y| x - y, |x, y| x - y, a)
 }
 
 fn multiplication(a: MalArgs) -> MalRet {
-    opArray(1, |x:i64, y:i64| {x*y}, |x:f32, y:f32| {x*y}, a)
+    opArray(1, i64::checked_mul, |x, y| x * y, |x, y| x * y, a)
 }
 
 fn division(a: MalArgs) -> MalRet {
-    if a.len() == 0 {
-        return Ok(Int(1));
-    }
-    if a.len() == 1 {
-        match &a[0] {
-            Int(i) => {
-                return Ok(Int(*i));
-            }
-            Float(f) => {
-                return Ok(Float(*f));
-            }
-            _ =>  return error("expecting (int/float, int/float, ...) args"),
-        }
-    }
-    for arg in a[1..].iter() {
+    for arg in a.iter().skip(1) {
         match arg {
-            Int(i) if *i == 0 => return error("division by zero"),
-            Float(f) if *f == 0.0 => return error("division by zero"),
+            Int(i) if *i == 0 => return Err(MalErr::DivisionByZero),
+            Float(f) if *f == 0.0 => return Err(MalErr::DivisionByZero),
             _ => {}
         }
     }
-    opArray(1, |x:i64, y:i64| {x/y}, |x:f32, y:f32| {x/y}, a)
+    // `i64::MIN / -1` overflows and is promoted like any other result.
+    let int_div = |x: i64, y: i64| x.checked_rem(y).filter(|r| *r == 0).and(x.checked_div(y));
+    opArray(1, int_div, |x, y| x / y, |x, y| x / y, a)
 }
 
-fn less_than(a: MalArgs) -> MalRet {
-    if a.len() != 2 {
-        return error("expecting exactly 2 args");
-    }
-    match (&a[0], &a[1]) {
-        (Int(a0), Int(a1)) => Ok(Bool(a0 < a1)),
-        (Float(a0), Float(a1)) => Ok(Bool(a0 < a1)),
-        (Float(a0), Int(a1)) => { Ok(Bool(a0 < &(*a1 as f32))) },
-        (Int(a0), Float(a1)) => { Ok(Bool(&(*a0 as f32) < a1)) },
-        _ => error("expecting (float/int, float/int) args"),
+fn numerator(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(_) | BigInt(_) => Ok(a[0].clone()),
+        Ratio(ref r) => Ok(bigint(r.numer().clone())),
+        _ => type_error("numerator: expecting a rational number"),
     }
 }
 
-fn less_equal(a: MalArgs) -> MalRet {
-    if a.len() != 2 {
-        return error("expecting exactly 2 args");
+fn denominator(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(_) | BigInt(_) => Ok(Int(1)),
+        Ratio(ref r) => Ok(bigint(r.denom().clone())),
+        _ => type_error("denominator: expecting a rational number"),
     }
-    match (&a[0], &a[1]) {
-        (Int(a0), Int(a1)) => Ok(Bool(a0 <= a1)),
-        (Float(a0), Float(a1)) => Ok(Bool(a0 <= a1)),
-        (Float(a0), Int(a1)) => { Ok(Bool(a0 <= &(*a1 as f32))) },
-        (Int(a0), Float(a1)) => { Ok(Bool(&(*a0 as f32) <= a1)) },
-        _ => error("expecting (float/int, float/int) args"),
+}
+
+// Orders two numbers; `None` if either is NaN.
+fn compare_numbers(x: &MalVal, y: &MalVal) -> Result<Option<Ordering>, MalErr> {
+    match (x, y) {
+        (Int(x), Int(y)) => Ok(Some(x.cmp(y))),
+        (Float(_), _) | (_, Float(_)) => match (as_float(x), as_float(y)) {
+            (Some(x), Some(y)) => Ok(x.partial_cmp(&y)),
+            _ => type_error("expecting (float/int, float/int) args"),
+        },
+        _ => match exact_operands(x, y) {
+            Some((x, y)) => Ok(Some(x.cmp(&y))),
+            None => type_error("expecting (float/int, float/int) args"),
+        },
     }
 }
 
-fn greater_than(a: MalArgs) -> MalRet {
+fn compare(a: &MalArgs) -> Result<Option<Ordering>, MalErr> {
     if a.len() != 2 {
-        return error("expecting exactly 2 args");
-    }
-    match (&a[0], &a[1]) {
-        (Int(a0), Int(a1)) => Ok(Bool(a0 > a1)),
-        (Float(a0), Float(a1)) => Ok(Bool(a0 > a1)),
-        (Float(a0), Int(a1)) => { Ok(Bool(a0 > &(*a1 as f32))) },
-        (Int(a0), Float(a1)) => { Ok(Bool(&(*a0 as f32) > a1)) },
-        _ => error("expecting (float/int, float/int) args"),
+        return arity_error("expecting exactly 2 args");
     }
+    compare_numbers(&a[0], &a[1])
+}
+
+fn less_than(a: MalArgs) -> MalRet {
+    Ok(Bool(matches!(compare(&a)?, Some(Ordering::Less))))
+}
+
+fn less_equal(a: MalArgs) -> MalRet {
+    Ok(Bool(matches!(compare(&a)?, Some(Ordering::Less | Ordering::Equal))))
+}
+
+fn greater_than(a: MalArgs) -> MalRet {
+    Ok(Bool(matches!(compare(&a)?, Some(Ordering::Greater))))
 }
 
 fn greater_equal(a: MalArgs) -> MalRet {
-    if a.len() != 2 {
-        return error("expecting exactly 2 args");
-    }
-    match (&a[0], &a[1]) {
-        (Int(a0), Int(a1)) => Ok(Bool(a0 >= a1)),
-        (Float(a0), Float(a1)) => Ok(Bool(a0 >= a1)),
-        (Float(a0), Int(a1)) => { Ok(Bool(a0 >= &(*a1 as f32))) },
-        (Int(a0), Float(a1)) => { Ok(Bool(&(*a0 as f32) >= a1)) },
-        _ => error("expecting (float/int, float/int) args"),
-    }
-}
\ No newline at end of file
+    Ok(Bool(matches!(compare(&a)?, Some(Ordering::Greater | Ordering::Equal))))
+}
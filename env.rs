@@ -1,70 +1,156 @@
 use alloc::rc::Rc;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use alloc::vec::Vec;
-use alloc::string::{String, ToString};
 
+use crate::symbol::Symbol;
 use crate::FnvHashMap;
 
-use crate::types::MalVal::{List, Sym, Vector};
-use crate::types::{error, list, MalRet, MalVal};
+use crate::types::MalVal::Sym;
+use crate::types::{arity_error, error, list, syntax_error, type_error, MalErr, MalRet, MalVal};
 
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    data: RefCell<FnvHashMap<Symbol, MalVal>>,
+    // Locals of compiled code, addressed by position (see `compiler.rs`).
+    slots: RefCell<Vec<MalVal>>,
     outer: Option<Env>,
+    state: Rc<EvalState>,
 }
 
 pub type Env = Rc<EnvStruct>;
 
+/// Default limit of `env_set_max_depth`: deep enough for ordinary
+/// recursion, shallow enough for an optimized build to stay within an
+/// 8 MiB stack. Debug builds use several times more stack per level.
+pub const DEFAULT_MAX_DEPTH: usize = 4096;
+
+/// Evaluation settings and limits shared by a root environment and every
+/// environment created from it (`let*` scopes, function calls, closures).
+pub struct EvalState {
+    // Whether `eval` compiles to bytecode instead of walking the AST. Fixed
+    // when the root environment is created.
+    bytecode: bool,
+    // Remaining evaluation steps; `None` means unlimited.
+    fuel: Cell<Option<u64>>,
+    // Current and maximum nesting of `eval` (or VM call frames).
+    depth: Cell<usize>,
+    max_depth: Cell<Option<usize>>,
+    // Polled on every evaluation step; returning true interrupts evaluation.
+    interrupt: RefCell<Option<Rc<dyn Fn() -> bool>>>,
+}
+
+impl Default for EvalState {
+    fn default() -> Self {
+        EvalState {
+            bytecode: false,
+            fuel: Cell::new(None),
+            depth: Cell::new(0),
+            max_depth: Cell::new(Some(DEFAULT_MAX_DEPTH)),
+            interrupt: RefCell::new(None),
+        }
+    }
+}
+
 // TODO: it would be nice to use impl here but it doesn't work on
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
+    let state = match &outer {
+        Some(o) => o.state.clone(),
+        None => Rc::new(EvalState::default()),
+    };
+    env_with_state(outer, state)
+}
+
+/// Creates a root environment whose `eval`, `rep` and function calls use
+/// the bytecode compiler and VM instead of the AST-walking evaluator. The
+/// choice cannot change later: closures created under the VM keep their
+/// locals in slots, which the AST walker cannot look up by name.
+pub fn env_new_bytecode() -> Env {
+    env_with_state(None, Rc::new(EvalState { bytecode: true, ..EvalState::default() }))
+}
+
+fn env_with_state(outer: Option<Env>, state: Rc<EvalState>) -> Env {
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(Vec::new()),
         outer,
+        state,
     })
 }
 
 // TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Env, mbinds: &MalVal, exprs: Vec<MalVal>) -> Result<Env, MalVal> {
+pub fn env_bind(outer: Env, mbinds: &MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
     let env = env_new(Some(outer));
-    match mbinds {
-        List(binds, _) | Vector(binds, _) => {
+    bind_params(mbinds, exprs, |b, val| env_set(&env, b, val).map(|_| ()))?;
+    Ok(env)
+}
+
+/// Like `env_bind`, but stores the arguments in slots in parameter order,
+/// for function bodies compiled with lexical addressing.
+pub(crate) fn env_bind_slots(outer: Env, mbinds: &MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+    let env = env_new(Some(outer));
+    bind_params(mbinds, exprs, |b, val| match b {
+        Sym(_) => {
+            env.slots.borrow_mut().push(val);
+            Ok(())
+        }
+        _ => type_error("Env.set called with non-Str"),
+    })?;
+    Ok(env)
+}
+
+// Matches arguments to parameters, calling `set` for each binding in order.
+fn bind_params(
+    mbinds: &MalVal,
+    exprs: Vec<MalVal>,
+    mut set: impl FnMut(&MalVal, MalVal) -> Result<(), MalErr>,
+) -> Result<(), MalErr> {
+    match mbinds.as_seq() {
+        Some(binds) => {
             let mut has_variadic = false;
             for (i, b) in binds.iter().enumerate() {
                 match b {
                     Sym(s) if s == "&" => {
-                        env_set(&env, &binds[i + 1], list(exprs[i..].to_vec()))?;
+                        set(&binds[i + 1], list(exprs[i..].to_vec()))?;
                         has_variadic = true;
                         break;
                     }
                     _ => {
                         if i >= exprs.len() {
-                            return error("wrong number of arguments: function requires more arguments than provided");
+                            return arity_error("wrong number of arguments: function requires more arguments than provided");
                         }
-                        env_set(&env, b, exprs[i].clone())?;
+                        set(b, exprs[i].clone())?;
                     }
                 }
             }
             // Check for too many arguments (only if not variadic)
             if !has_variadic && exprs.len() > binds.len() {
-                return error("wrong number of arguments: function received more arguments than expected");
+                return arity_error("wrong number of arguments: function received more arguments than expected");
             }
-            Ok(env)
+            Ok(())
         }
-        _ => error("env_bind binds not List/Vector"),
+        None => syntax_error("env_bind binds not List/Vector"),
     }
 }
 
 pub fn env_get(env: &Env, key: &str) -> Option<MalVal> {
+    // A name that was never interned cannot be bound anywhere.
+    env_get_sym(env, Symbol::find(key)?)
+}
+
+/// Like `env_get`, for an already interned name. Environments holding only
+/// slots (function calls and scopes of compiled code) are skipped without
+/// hashing `key`.
+pub(crate) fn env_get_sym(env: &Env, key: Symbol) -> Option<MalVal> {
     let mut mut_env = env;
     loop {
-        if let Some(value) = mut_env.data.borrow().get(key) {
+        let data = mut_env.data.borrow();
+        if !data.is_empty() && let Some(value) = data.get(&key) {
             return Some(value.clone());
-        } else if let Some(outer) = &mut_env.outer {
-            mut_env = outer;
-        } else {
-            return None;
+        }
+        match &mut_env.outer {
+            Some(outer) => mut_env = outer,
+            None => return None,
         }
     }
 }
@@ -72,13 +158,141 @@ pub fn env_get(env: &Env, key: &str) -> Option<MalVal> {
 pub fn env_set(env: &Env, key: &MalVal, val: MalVal) -> MalRet {
     match key {
         Sym(s) => {
-            env_sets(env, s, val.clone());
+            env.data.borrow_mut().insert(*s, val.clone());
             Ok(val)
         }
-        _ => error("Env.set called with non-Str"),
+        _ => type_error("Env.set called with non-Str"),
     }
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    env.data.borrow_mut().insert(Symbol::intern(key), val);
+}
+
+/// Reads slot `index` of the environment `depth` levels out from `env`.
+/// Slots that were never set (a conditional `def!` shadowing a local, see
+/// `compiler.rs`) read as `nil`.
+pub(crate) fn env_get_slot(env: &Env, depth: usize, index: usize) -> MalRet {
+    let mut env = env;
+    for _ in 0..depth {
+        env = match &env.outer {
+            Some(outer) => outer,
+            None => return error("compiled code run outside its scope"),
+        };
+    }
+    Ok(env.slots.borrow().get(index).cloned().unwrap_or(MalVal::Nil))
+}
+
+pub(crate) fn env_set_slot(env: &Env, index: usize, val: MalVal) {
+    let mut slots = env.slots.borrow_mut();
+    if index >= slots.len() {
+        slots.resize(index + 1, MalVal::Nil);
+    }
+    slots[index] = val;
+}
+
+/// Whether `env` runs on the bytecode VM (see `env_new_bytecode`).
+pub fn env_bytecode(env: &Env) -> bool {
+    env.state.bytecode
+}
+
+/// Limits the number of evaluation steps left for `env` and every
+/// environment sharing its state; `None` removes the limit. Each item the
+/// core sequence functions (`range`, `reduce`, `sort`...) loop over costs a
+/// step too.
+pub fn env_set_fuel(env: &Env, steps: Option<u64>) {
+    env.state.fuel.set(steps);
+}
+
+/// Registers a callback polled on every evaluation step and periodically by
+/// the core sequence functions (so it must be cheap, e.g. reading a flag set
+/// by a Ctrl-C handler or a UART interrupt).
+/// When it returns true evaluation unwinds with `MalErr::Interrupted`; the
+/// callback is responsible for clearing its own flag. `None` removes it.
+pub fn env_set_interrupt(env: &Env, check: Option<Rc<dyn Fn() -> bool>>) {
+    *env.state.interrupt.borrow_mut() = check;
+}
+
+/// Evaluation steps left, or `None` when unlimited.
+pub fn env_fuel(env: &Env) -> Option<u64> {
+    env.state.fuel.get()
+}
+
+fn check_interrupt(state: &EvalState) -> Result<(), MalErr> {
+    // Cloned so the callback itself may replace the hook.
+    let check = state.interrupt.borrow().clone();
+    match check {
+        Some(check) if check() => Err(MalErr::Interrupted),
+        _ => Ok(()),
+    }
+}
+
+/// The interrupt hook and step budget of an environment, for natives that
+/// loop without evaluating Lisp code (`range`, `reduce` with a native
+/// function...).
+#[derive(Clone)]
+pub struct Interrupt(Rc<EvalState>);
+
+impl Interrupt {
+    /// A hook that never fires, for natives registered outside any
+    /// environment.
+    pub fn none() -> Interrupt {
+        Interrupt(Rc::new(EvalState::default()))
+    }
+
+    /// Charges one evaluation step for item `i` of a native loop, and polls
+    /// the hook on every 1024th item.
+    pub fn check(&self, i: usize) -> Result<(), MalErr> {
+        if i % 1024 == 0 {
+            check_interrupt(&self.0)?;
+        }
+        spend_fuel(&self.0)
+    }
+}
+
+/// The interrupt hook natives created for `env` should poll.
+pub fn env_interrupt(env: &Env) -> Interrupt {
+    Interrupt(env.state.clone())
+}
+
+/// Accounts for one evaluation step, failing once the fuel is spent.
+pub fn env_tick(env: &Env) -> Result<(), MalErr> {
+    check_interrupt(&env.state)?;
+    spend_fuel(&env.state)
+}
+
+fn spend_fuel(state: &EvalState) -> Result<(), MalErr> {
+    match state.fuel.get() {
+        None => Ok(()),
+        Some(0) => Err(MalErr::BudgetExhausted),
+        Some(n) => {
+            state.fuel.set(Some(n - 1));
+            Ok(())
+        }
+    }
+}
+
+/// Limits how deeply `eval` may nest on the native stack (non-tail calls,
+/// argument and collection evaluation, natives calling back into Lisp)
+/// before failing with `MalErr::DepthExceeded`; `None` removes the limit,
+/// which is `DEFAULT_MAX_DEPTH` until set. Under the bytecode VM only
+/// non-tail calls count.
+pub fn env_set_max_depth(env: &Env, depth: Option<usize>) {
+    env.state.max_depth.set(depth);
+}
+
+/// Enters one level of nested evaluation; pair with `env_leave`.
+pub fn env_enter(env: &Env) -> Result<(), MalErr> {
+    let depth = env.state.depth.get() + 1;
+    match env.state.max_depth.get() {
+        Some(max) if depth > max => Err(MalErr::DepthExceeded(max)),
+        _ => {
+            env.state.depth.set(depth);
+            Ok(())
+        }
+    }
+}
+
+pub fn env_leave(env: &Env) {
+    env.state.depth.set(env.state.depth.get() - 1);
 }
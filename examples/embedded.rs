@@ -79,7 +79,7 @@ fn main() {
                 // In embedded: uart.write_str(&result), display.print(&result), etc.
             }
             Err(e) => {
-                println!("Error: {}\n", e.pr_str(true));
+                println!("Error: {}\n", e);
                 // In embedded: log error, set LED, store in buffer, etc.
             }
         }
@@ -2,6 +2,8 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 use alloc::rc::Rc;
 use alloc::vec;
@@ -19,17 +21,35 @@ extern crate hashbrown;
 // Define FnvHashMap for no_std using hashbrown + FnvBuildHasher
 pub type FnvHashMap<K, V> = hashbrown::HashMap<K, V, fnv::FnvBuildHasher>;
 
+pub mod symbol;
+pub use crate::symbol::Symbol;
 pub mod system;
-pub use crate::system::SystemOps;
+pub use crate::system::{FsError, FsErrorKind, MemSystemOps, SystemOps};
+#[cfg(feature = "std")]
+pub use crate::system::StdSystemOps;
 
 #[macro_use]
 pub mod types;
-pub use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, NativeClosure, Nil, Str, Sym, Vector};
-pub use crate::types::{error, list, vector, FuncStruct, MalArgs, MalRet, MalVal};
+pub use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, NativeClosure, Nil, Set, Str, Sym, Vector};
+pub use crate::types::{
+    bigint, error, list, ratio, vector, Frame, FuncStruct, MalArgs, MalBigInt, MalErr, MalFloat, MalRatio, MalRet, MalVal,
+};
+use crate::types::{nesting_error, new_map, new_set, syntax_error, type_error, MAX_NESTING};
+use crate::compiler::Code;
+use crate::env::env_get_sym;
+use crate::symbol::SpecialForm;
 pub mod env;
 pub mod printer;
 pub mod reader;
-pub use crate::env::{env_bind, env_get, env_new, env_set, env_sets, Env};
+pub use crate::env::{
+    env_bind, env_bytecode, env_enter, env_fuel, env_get, env_interrupt, env_leave, env_new, env_new_bytecode,
+    env_set, env_set_fuel, env_set_interrupt, env_set_max_depth, env_sets, env_tick, Env, Interrupt,
+    DEFAULT_MAX_DEPTH,
+};
+pub mod compiler;
+pub mod vm;
+#[cfg(feature = "regex")]
+pub mod regex;
 #[macro_use]
 pub mod core;
 pub use crate::core::ns;
@@ -39,16 +59,22 @@ impl MalVal {
         match self {
             Func(f, _) => f(args),
             NativeClosure(f, _) => f(args),
+            MalFunc(f) if env_bytecode(&f.env) => vm::call(f, args),
             MalFunc(FuncStruct {
                 ast,
                 env,
                 params,
+                name,
                 ..
             }) => {
-                let fn_env = &env_bind(env.clone(), params, args)?;
-                eval(ast, fn_env)
+                let frame = || Frame {
+                    name: name.clone(),
+                    form: None,
+                };
+                let fn_env = &env_bind(env.clone(), params, args).map_err(|e| e.push_frame(frame()))?;
+                eval(ast, fn_env).map_err(|e| e.push_frame(frame()))
             }
-            _ => error("attempt to call non-function"),
+            _ => type_error("attempt to call non-function"),
         }
     }
 }
@@ -60,43 +86,87 @@ pub fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
-    let mut acc = list!();
-    for elt in elts.iter().rev() {
-        if let List(v, _) = elt {
-            if v.len() == 2 {
-                if let Sym(ref s) = v[0] {
-                    if s == "splice-unquote" {
-                        acc = list!(Sym("concat".to_string()), v[1].clone(), acc);
-                        continue;
-                    }
+// Expands the elements of a quasiquoted list or vector as
+// `(concat (list a b) spliced (list c))`, which nests no deeper however long
+// the sequence is.
+fn qq_iter(elts: &[MalVal], depth: usize) -> MalRet {
+    let mut parts = vec![Sym(Symbol::intern("concat"))];
+    let mut run = vec![Sym(Symbol::intern("list"))];
+    for elt in elts {
+        match elt {
+            List(v, _) if v.len() == 2 && matches!(&v[0], Sym(s) if s.special() == Some(SpecialForm::SpliceUnquote)) => {
+                if run.len() > 1 {
+                    parts.push(list(::core::mem::replace(&mut run, vec![Sym(Symbol::intern("list"))])));
                 }
+                parts.push(v[1].clone());
             }
+            _ => run.push(qq(elt, depth + 1)?),
         }
-        acc = list!(Sym("cons".to_string()), quasiquote(elt), acc);
     }
-    acc
+    if run.len() > 1 {
+        parts.push(list(run));
+    }
+    Ok(list(parts))
+}
+
+pub(crate) fn quasiquote(ast: &MalVal) -> MalRet {
+    qq(ast, 0)
 }
 
-fn quasiquote(ast: &MalVal) -> MalVal {
+fn qq(ast: &MalVal, depth: usize) -> MalRet {
+    if depth > MAX_NESTING {
+        return nesting_error();
+    }
     match ast {
         List(v, _) => {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
-                    if s == "unquote" {
-                        return v[1].clone();
+                    if s.special() == Some(SpecialForm::Unquote) {
+                        return Ok(v[1].clone());
                     }
                 }
             }
-            qq_iter(v)
+            qq_iter(v, depth)
         }
-        Vector(v, _) => list!(Sym("vec".to_string()), qq_iter(v)),
-        Hash(_, _) | Sym(_) => list!(Sym("quote".to_string()), ast.clone()),
-        _ => ast.clone(),
+        Vector(v, _) => Ok(list!(Sym(Symbol::intern("vec")), qq_iter(&v.iter().cloned().collect::<Vec<_>>(), depth)?)),
+        Hash(_, _) | Set(_, _) | Sym(_) => Ok(list!(Sym(Symbol::intern("quote")), ast.clone())),
+        _ => Ok(ast.clone()),
+    }
+}
+
+// Names an anonymous function after the symbol it is being bound to.
+pub(crate) fn named(val: MalVal, key: &MalVal) -> MalVal {
+    match (val, key) {
+        (MalFunc(f @ FuncStruct { name: None, .. }), Sym(s)) => MalFunc(FuncStruct {
+            name: Some(Rc::from(s.as_str())),
+            ..f
+        }),
+        (val, _) => val,
     }
 }
 
-pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
+// Value of `*stack-trace*` in a `catch*` handling `exc`.
+pub(crate) fn stack_trace(exc: &MalErr) -> MalVal {
+    vector(exc.trace().iter().map(Frame::to_value).collect())
+}
+
+pub fn eval(ast: &MalVal, env: &Env) -> MalRet {
+    if env_bytecode(env) {
+        return vm::eval(ast, env);
+    }
+    env_enter(env)?;
+    let mut frame = None;
+    let res = eval_tco(ast, env, &mut frame).map_err(|e| match frame {
+        Some(frame) => e.push_frame(frame),
+        None => e,
+    });
+    env_leave(env);
+    res
+}
+
+// `frame` tracks the function whose body is being evaluated, so that an
+// error escaping the loop can be traced to it. Tail calls replace it.
+fn eval_tco(orig_ast: &MalVal, orig_env: &Env, frame: &mut Option<Frame>) -> MalRet {
     let mut ast = orig_ast;
     let mut env = orig_env;
     // These variables ensure a sufficient lifetime for the data
@@ -105,11 +175,12 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
     let mut live_env;
 
     'tco: loop {
+        env_tick(env)?;
         // Debug eval is disabled in no_std builds
         match ast {
-            Sym(s) => match env_get(env, s) {
+            Sym(s) => match env_get_sym(env, *s) {
                 Some(r) => return Ok(r),
-                None => return error(&format!("'{}' not found", s)),
+                None => return Err(MalErr::Unbound(s.to_string())),
             },
             Vector(v, _) => {
                 let mut lst: MalArgs = vec![];
@@ -119,47 +190,58 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                 return Ok(vector(lst));
             }
             Hash(hm, _) => {
-                let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+                let mut new_hm = new_map();
                 for (k, v) in hm.iter() {
-                    new_hm.insert(k.to_string(), eval(v, env)?);
+                    new_hm.insert_mut(eval(k, env)?, eval(v, env)?);
                 }
-                return Ok(Hash(Rc::new(new_hm), Rc::new(Nil)));
+                return Ok(Hash(new_hm, Rc::new(Nil)));
+            }
+            Set(s, _) => {
+                let mut new_s = new_set();
+                for v in s.iter() {
+                    new_s.insert_mut(eval(v, env)?);
+                }
+                return Ok(Set(new_s, Rc::new(Nil)));
             }
             List(l, _) => {
                 if l.is_empty() {
                     return Ok(ast.clone());
                 }
                 let a0 = &l[0];
-                match a0 {
-                    Sym(a0sym) if a0sym == "def!" => {
-                        return env_set(env, &l[1], eval(&l[2], env)?);
+                let special = match a0 {
+                    Sym(s) => s.special(),
+                    _ => None,
+                };
+                match special {
+                    Some(SpecialForm::Def) => {
+                        return env_set(env, &l[1], named(eval(&l[2], env)?, &l[1]));
                     }
-                    Sym(a0sym) if a0sym == "let*" => {
+                    Some(SpecialForm::Let) => {
                         live_env = env_new(Some(env.clone()));
                         env = &live_env;
                         let (a1, a2) = (&l[1], &l[2]);
-                        match a1 {
-                            List(binds, _) | Vector(binds, _) => {
+                        match a1.as_seq() {
+                            Some(binds) => {
                                 for (b, e) in binds.iter().tuples() {
                                     let val = eval(e, env)?;
                                     env_set(env, b, val)?;
                                 }
                             }
-                            _ => {
-                                return error("let* with non-List bindings");
+                            None => {
+                                return syntax_error("let* with non-List bindings");
                             }
                         };
                         live_ast = a2.clone();
                         ast = &live_ast;
                         continue 'tco;
                     }
-                    Sym(a0sym) if a0sym == "quote" => return Ok(l[1].clone()),
-                    Sym(a0sym) if a0sym == "quasiquote" => {
-                        live_ast = quasiquote(&l[1]);
+                    Some(SpecialForm::Quote) => return Ok(l[1].clone()),
+                    Some(SpecialForm::Quasiquote) => {
+                        live_ast = quasiquote(&l[1])?;
                         ast = &live_ast;
                         continue 'tco;
                     }
-                    Sym(a0sym) if a0sym == "defmacro!" => {
+                    Some(SpecialForm::DefMacro) => {
                         let (a1, a2) = (&l[1], &l[2]);
                         let r = eval(a2, env)?;
                         match r {
@@ -167,37 +249,41 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                                 return env_set(
                                     env,
                                     a1,
-                                    MalFunc(FuncStruct {
-                                        is_macro: true,
-                                        ..f.clone()
-                                    }),
+                                    named(
+                                        MalFunc(FuncStruct {
+                                            is_macro: true,
+                                            ..f.clone()
+                                        }),
+                                        a1,
+                                    ),
                                 )
                             }
-                            _ => return error("set_macro on non-function"),
+                            _ => return type_error("set_macro on non-function"),
                         }
                     }
-                    Sym(a0sym) if a0sym == "try*" => {
+                    Some(SpecialForm::Try) => {
                         if l.len() < 3 {
                             live_ast = l[1].clone();
                             ast = &live_ast;
                             continue 'tco;
                         }
                         match eval(&l[1], env) {
-                            Err(exc) => match &l[2] {
+                            Err(exc) if exc.is_catchable() => match &l[2] {
                                 List(c, _) => {
                                     live_env = env_new(Some(env.clone()));
                                     env = &live_env;
-                                    env_set(env, &c[1], exc)?;
+                                    env_set(env, &c[1], exc.to_value())?;
+                                    env_sets(env, "*stack-trace*", stack_trace(&exc));
                                     live_ast = c[2].clone();
                                     ast = &live_ast;
                                     continue 'tco;
                                 }
-                                _ => return error("invalid catch block"),
+                                _ => return syntax_error("invalid catch block"),
                             },
                             res => return res,
                         }
                     }
-                    Sym(a0sym) if a0sym == "do" => {
+                    Some(SpecialForm::Do) => {
                         if l.len() == 1 {
                             return Ok(Nil);
                         }
@@ -208,7 +294,7 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                         ast = &live_ast;
                         continue 'tco;
                     }
-                    Sym(a0sym) if a0sym == "if" => {
+                    Some(SpecialForm::If) => {
                         let cond = eval(&l[1], env)?;
                         match cond {
                             Bool(false) | Nil if l.len() >= 4 => {
@@ -225,7 +311,7 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             _ => return Ok(Nil),
                         }
                     }
-                    Sym(a0sym) if a0sym == "fn*" => {
+                    Some(SpecialForm::Fn) => {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         return Ok(MalFunc(FuncStruct {
                             ast: Rc::new(a2),
@@ -233,6 +319,8 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            name: None,
+                            code: Rc::new(Code::default()),
                         }));
                     }
                     _ => match eval(a0, env)? {
@@ -256,23 +344,37 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
                             }
                             return f.apply(args);
                         }
+                        // A function defined under the VM runs there, even
+                        // when tree-walking code calls it.
+                        MalFunc(f) if env_bytecode(&f.env) => {
+                            let mut args: MalArgs = vec![];
+                            for i in 1..l.len() {
+                                args.push(eval(&l[i], env)?);
+                            }
+                            return vm::call(&f, args);
+                        }
                         MalFunc(FuncStruct {
                             ast: mast,
                             env: menv,
                             params: mparams,
+                            name,
                             ..
                         }) => {
                             let mut args: MalArgs = vec![];
                             for i in 1..l.len() {
                                 args.push(eval(&l[i], env)?);
                             }
+                            *frame = Some(Frame {
+                                name,
+                                form: Some(ast.clone()),
+                            });
                             live_env = env_bind(menv.clone(), &mparams, args)?;
                             env = &live_env;
                             live_ast = (*mast).clone();
                             ast = &live_ast;
                             continue 'tco;
                         }
-                        _ => return error("attempt to call non-function"),
+                        _ => return type_error("attempt to call non-function"),
                     },
                 }
             }
@@ -281,6 +383,18 @@ pub fn eval(orig_ast: &MalVal, orig_env: &Env) -> MalRet {
     } // end 'tco loop
 }
 
+/// Evaluates `ast` with at most `steps` evaluation steps, on top of any
+/// limit already set on `env`. Steps used count against that limit.
+pub fn eval_with_fuel(ast: &MalVal, env: &Env, steps: u64) -> MalRet {
+    let outer = env_fuel(env);
+    let budget = outer.map_or(steps, |o| o.min(steps));
+    env_set_fuel(env, Some(budget));
+    let res = eval(ast, env);
+    let used = budget - env_fuel(env).unwrap_or(0);
+    env_set_fuel(env, outer.map(|o| o - used));
+    res
+}
+
 // print
 pub fn print(ast: &MalVal) -> String {
     ast.pr_str(true)
@@ -289,7 +403,7 @@ pub fn print(ast: &MalVal) -> String {
 /// REPL variants
 
 /// Main REPL function: Read-Eval-Print
-pub fn rep(str: &str, env: &Env) -> Result<String, MalVal> {
+pub fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     let ast = read(str)?;
     let exp = eval(&ast, env)?;
     Ok(print(&exp))
@@ -318,9 +432,9 @@ pub fn rep_stream_to_string<'a>(
     mal_stream.map(move |result: MalRet| match result {
         Ok(expr) => match eval(&expr, env) {
             Ok(val) => print(&val),
-            Err(e) => format!("Error: {}", e.pr_str(true)),
+            Err(e) => format!("Error: {}", e),
         },
-        Err(e) => format!("Parse error: {}", e.pr_str(true)),
+        Err(e) => format!("Parse error: {}", e),
     })
 }
 
@@ -360,22 +474,50 @@ pub fn rep_stream_to_string_vec<'a>(
 
 /// Initialize a new MAL environment with core functions
 pub fn mal_env() -> Env {
-    let repl_env = env_new(None);
+    core_env(env_new(None))
+}
 
+/// Like `mal_env`, running on the bytecode compiler and VM (see
+/// `env_new_bytecode`)
+pub fn mal_env_bytecode() -> Env {
+    core_env(env_new_bytecode())
+}
+
+fn core_env(repl_env: Env) -> Env {
     // core.rs: defined using rust
-    env_sets(&repl_env, "eval", types::func(|a| {
+    // eval runs in the REPL environment itself, so it shares its limits.
+    // A weak reference avoids a cycle between the environment and the closure.
+    let weak_env = Rc::downgrade(&repl_env);
+    env_sets(&repl_env, "eval", types::func_closure(move |a| {
         let ast = &a[0];
-        let env = env_new(None);
-        eval(ast, &env)
+        match weak_env.upgrade() {
+            Some(env) => eval(ast, &env),
+            None => error("eval: environment no longer exists"),
+        }
     }));
 
-    for (k, v) in core::ns() {
+    for (k, v) in core::ns_with_interrupt(&env_interrupt(&repl_env)) {
         env_sets(&repl_env, k, v);
     }
 
     repl_env
 }
 
+/// Initialize a new MAL environment with core functions plus the host
+/// builtins (files, `readline`, `time/*`, `prn`, `println`...) backed by `sys`
+pub fn mal_env_with_system(sys: Rc<dyn SystemOps>) -> Env {
+    let repl_env = mal_env();
+    for (k, v) in system::ns(sys) {
+        env_sets(&repl_env, k, v);
+    }
+    re("(defmacro! with-out-str (fn* (& body) (list 'with-out-str* (list 'fn* () (cons 'do body)))))", &repl_env);
+    re(
+        r#"(def! load-file (fn* (f) (eval (read-string (str "(do " (slurp f) "\nnil)")))))"#,
+        &repl_env,
+    );
+    repl_env
+}
+
 /// Initialize a MAL environment with all standard library definitions
 pub fn initialize_mal_env(repl_env: &Env, argv: Vec<String>) {
     // Set *ARGV*
@@ -384,10 +526,6 @@ pub fn initialize_mal_env(repl_env: &Env, argv: Vec<String>) {
     // core.mal: defined using the language itself
     re("(def! *host-language* \"rust\")", repl_env);
     re("(def! not (fn* (a) (if a false true)))", repl_env);
-    re(
-        r#"(def! load-file (fn* (f) (eval (read-string (str "(do " (slurp f) "\nnil)")))))"#,
-        repl_env,
-    );
     re("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))",
         repl_env);
 }
@@ -4,9 +4,9 @@ use alloc::vec::Vec;
 use alloc::format;
 
 use crate::types::MalVal::{
-    Atom, Bool, Func, Hash, Int, Float, Kwd, List, MalFunc, NativeClosure, Nil, Str, Sym, Vector,
+    Atom, BigInt, Bool, Char, Func, Hash, Int, Float, Kwd, List, MalFunc, NativeClosure, Nil, Ratio, Set, Str, Sym, Vector,
 };
-use crate::types::{unwrap_map_key, FuncStruct, MalVal};
+use crate::types::{FuncStruct, MalVal, MAX_NESTING};
 
 fn escape_str(s: &str) -> String {
     s.chars()
@@ -20,43 +20,155 @@ fn escape_str(s: &str) -> String {
         .join("")
 }
 
+// Text after the `\` of a character literal.
+fn char_name(c: char) -> String {
+    match c {
+        '\n' => String::from("newline"),
+        ' ' => String::from("space"),
+        '\t' => String::from("tab"),
+        '\r' => String::from("return"),
+        '\u{8}' => String::from("backspace"),
+        '\u{c}' => String::from("formfeed"),
+        c if c.is_control() => format!("u{:04X}", c as u32),
+        c => c.to_string(),
+    }
+}
+
 impl MalVal {
     pub fn pr_str(&self, print_readably: bool) -> String {
-        match self {
-            Nil => String::from("nil"),
-            Bool(true) => String::from("true"),
-            Bool(false) => String::from("false"),
-            Int(i) => format!("{}", i),
-            Float(f)    => format!("{}", f),
-            Kwd(s) => format!(":{}", s),
-            Str(s) => {
-                if print_readably {
-                    format!("\"{}\"", escape_str(s))
-                } else {
-                    s.clone()
-                }
-            }
-            Sym(s) => s.clone(),
-            List(l, _) => pr_seq(l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(l, print_readably, "[", "]", " "),
+        let mut out = String::new();
+        pr_into(&mut out, vec![Piece::Val(self, print_readably, 0)]);
+        out
+    }
+}
+
+pub fn pr_seq(seq: &[MalVal], print_readably: bool, start: &str, end: &str, join: &str) -> String {
+    let mut out = String::from(start);
+    for (i, x) in seq.iter().enumerate() {
+        if i > 0 {
+            out.push_str(join);
+        }
+        out.push_str(&x.pr_str(print_readably));
+    }
+    out.push_str(end);
+    out
+}
+
+// What is left to print: a value found a number of collections deep, or
+// literal text between values.
+enum Piece<'a> {
+    Val(&'a MalVal, bool, usize),
+    Text(&'static str),
+}
+
+// Prints the pieces on `todo`, last first, onto `out`. Collections push
+// their elements back onto `todo` instead of recursing, so deeply nested
+// values cannot overflow the native stack; anything nested deeper than
+// `MAX_NESTING`, which the reader would reject anyway, prints as `...`.
+fn pr_into<'a>(out: &mut String, mut todo: Vec<Piece<'a>>) {
+    while let Some(piece) = todo.pop() {
+        let (val, print_readably, depth) = match piece {
+            Piece::Text(t) => {
+                out.push_str(t);
+                continue;
+            }
+            Piece::Val(_, _, depth) if depth > MAX_NESTING => {
+                out.push_str("...");
+                continue;
+            }
+            Piece::Val(val, print_readably, depth) => (val, print_readably, depth),
+        };
+        let items: Vec<&'a MalVal> = match val {
+            Nil => {
+                out.push_str("nil");
+                continue;
+            }
+            Bool(true) => {
+                out.push_str("true");
+                continue;
+            }
+            Bool(false) => {
+                out.push_str("false");
+                continue;
+            }
+            List(l, _) => {
+                out.push('(');
+                todo.push(Piece::Text(")"));
+                l.iter().collect()
+            }
+            Vector(l, _) => {
+                out.push('[');
+                todo.push(Piece::Text("]"));
+                l.iter().collect()
+            }
             Hash(hm, _) => {
-                let l: Vec<MalVal> = hm
-                    .iter()
-                    .flat_map(|(k, v)| vec![unwrap_map_key(k), v.clone()])
-                    .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+                out.push('{');
+                todo.push(Piece::Text("}"));
+                hm.iter().flat_map(|(k, v)| [k, v]).collect()
+            }
+            Set(s, _) => {
+                out.push_str("#{");
+                todo.push(Piece::Text("}"));
+                s.iter().collect()
             }
-            Func(_, _) => String::from("#<builtin>"),
             MalFunc(FuncStruct {
                 ast: a, params: p, ..
-            }) => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
-            NativeClosure(_, _) => String::from("#<native-closure>"),
-            Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            }) => {
+                out.push_str("(fn* ");
+                todo.extend([Piece::Text(")"), Piece::Val(a, true, depth + 1), Piece::Text(" ")]);
+                todo.push(Piece::Val(p, true, depth + 1));
+                continue;
+            }
+            Atom(a) => {
+                // The contents are borrowed only while they print, so they
+                // print on their own; atoms are the only way to build a cycle,
+                // which the depth limit cuts off.
+                out.push_str("(atom ");
+                pr_into(out, vec![Piece::Val(&a.borrow(), true, depth + 1)]);
+                out.push(')');
+                continue;
+            }
+            _ => {
+                out.push_str(&pr_scalar(val, print_readably));
+                continue;
+            }
+        };
+        for (i, x) in items.into_iter().enumerate().rev() {
+            todo.push(Piece::Val(x, print_readably, depth + 1));
+            if i > 0 {
+                todo.push(Piece::Text(" "));
+            }
         }
     }
 }
 
-pub fn pr_seq(seq: &[MalVal], print_readably: bool, start: &str, end: &str, join: &str) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
-    format!("{}{}{}", start, strs.join(join), end)
+// Prints a value that holds no other values.
+fn pr_scalar(val: &MalVal, print_readably: bool) -> String {
+    match val {
+        Int(i) => format!("{}", i),
+        Float(f) if f.is_nan() => String::from("##NaN"),
+        Float(f) if f.is_infinite() => String::from(if *f > 0.0 { "##Inf" } else { "##-Inf" }),
+        // Debug keeps a `.0` on whole floats so that they read back as floats.
+        Float(f)    => format!("{:?}", f),
+        BigInt(n)   => format!("{}", n),
+        Ratio(r)    => format!("{}", r),
+        Kwd(s) => format!(":{}", s),
+        Str(s) => {
+            if print_readably {
+                format!("\"{}\"", escape_str(s))
+            } else {
+                s.clone()
+            }
+        }
+        Char(c) if print_readably => format!("\\{}", char_name(*c)),
+        Char(c) => c.to_string(),
+        Sym(s) => s.to_string(),
+        #[cfg(feature = "regex")]
+        MalVal::Regex(r) if print_readably => format!("#\"{}\"", r.as_str()),
+        #[cfg(feature = "regex")]
+        MalVal::Regex(r) => r.as_str().to_string(),
+        Func(_, _) => String::from("#<builtin>"),
+        NativeClosure(_, _) => String::from("#<native-closure>"),
+        _ => unreachable!("pr_scalar called on a collection"),
+    }
 }
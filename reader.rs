@@ -4,37 +4,156 @@ use alloc::vec::Vec;
 use alloc::string::{String, ToString};
 use alloc::format;
 
+use num_traits::Num;
 use scanner::{Scanner, EOF};
 
-use crate::types::MalVal::{Bool, Int, Float, Kwd, List, Nil, Str, Sym};
-use crate::types::{error, hash_map, list, vector, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Char, Int, Float, Hash, Kwd, List, Nil, Set, Str, Sym, Vector};
+use crate::symbol::Symbol;
+use crate::types::{
+    bigint, hash_map, nesting_error, ratio, syntax_error, MalBigInt, MalErr, MalFloat, MalRatio, MalRet, MalVal, Span,
+    MAX_NESTING,
+};
+
+/// A token together with the source span it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub span: Span,
+}
 
 #[derive(Debug, Clone)]
 struct Reader {
-    tokens: Vec<String>,
+    tokens: Vec<Token>,
     pos: usize,
+    file: Option<String>,
 }
 
 impl Reader {
-    fn next(&mut self) -> Result<String, MalVal> {
+    fn next(&mut self) -> Result<Token, MalErr> {
         self.pos += 1;
         Ok(self
             .tokens
             .get(self.pos - 1)
-            .ok_or_else(|| Str("underflow".to_string()))?
-            .to_string())
+            .ok_or_else(|| MalErr::Incomplete("unexpected EOF".to_string()))?
+            .clone())
     }
-    fn peek(&self) -> Result<String, MalVal> {
+    fn peek(&self) -> Result<Token, MalErr> {
         Ok(self
             .tokens
             .get(self.pos)
-            .ok_or_else(|| Str("underflow".to_string()))?
-            .to_string())
+            .ok_or_else(|| MalErr::Incomplete("unexpected EOF".to_string()))?
+            .clone())
+    }
+    // Span running from `start` to the end of the last consumed token.
+    fn span_from(&self, start: &Span) -> Span {
+        let end = match self.pos {
+            0 => start,
+            pos => &self.tokens[pos - 1].span,
+        };
+        Span {
+            file: self.file.clone(),
+            line: start.line,
+            column: start.column,
+            end_line: end.end_line,
+            end_column: end.end_column,
+        }
+    }
+    // The token after the current one, if it follows it with nothing in
+    // between (as the `{` of a `#{` set literal does).
+    fn adjacent(&self) -> Option<&Token> {
+        let (cur, next) = (self.tokens.get(self.pos)?, self.tokens.get(self.pos + 1)?);
+        let touching = next.span.line == cur.span.end_line && next.span.column == cur.span.end_column + 1;
+        touching.then_some(next)
+    }
+    fn located(&self, span: &Span) -> Span {
+        Span {
+            file: self.file.clone(),
+            ..span.clone()
+        }
+    }
+}
+
+// Tracks line and column while walking the source in step with the scanner.
+struct Cursor<'a> {
+    src: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(src: &'a str) -> Self {
+        Cursor {
+            src,
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        for ch in self.src[self.offset..self.offset + n].chars() {
+            if ch == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.offset += n;
+    }
+
+    fn skip_separators(&mut self) {
+        let rest = &self.src[self.offset..];
+        let mut n = 0;
+        let mut in_comment = false;
+        for ch in rest.chars() {
+            if in_comment {
+                in_comment = ch != '\n';
+            } else if ch == ';' {
+                in_comment = true;
+            } else if !(ch.is_whitespace() || ch == ',') {
+                break;
+            }
+            n += ch.len_utf8();
+        }
+        self.advance(n);
+    }
+
+    // Moves past `text` and returns the span it covers. The scanner
+    // normally leaves only separators between tokens; if it skipped
+    // anything else we fall back to searching for the token text.
+    fn locate(&mut self, text: &str) -> Span {
+        self.skip_separators();
+        let found = match self.src[self.offset..].find(text) {
+            Some(0) => true,
+            Some(i) => {
+                self.advance(i);
+                true
+            }
+            None => false,
+        };
+        let (line, column) = (self.line, self.column);
+        let (mut end_line, mut end_column) = (line, column);
+        if found {
+            let last = text.chars().next_back().map_or(0, |c| text.len() - c.len_utf8());
+            self.advance(last);
+            (end_line, end_column) = (self.line, self.column);
+            self.advance(text.len() - last);
+        }
+        Span {
+            file: None,
+            line,
+            column,
+            end_line,
+            end_column,
+        }
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
+fn tokenize(str: &str) -> Vec<Token> {
     let mut scanner = Scanner::init(str.as_bytes());
+    let mut cursor = Cursor::new(str);
     let mut tokens = vec![];
 
     loop {
@@ -43,7 +162,9 @@ fn tokenize(str: &str) -> Vec<String> {
             break;
         }
         // Skip comments - scanner already handles them
-        tokens.push(scanner.token_text());
+        let text = scanner.token_text();
+        let span = cursor.locate(&text);
+        tokens.push(Token { text, span });
     }
 
     tokens
@@ -80,100 +201,275 @@ fn unescape_str(s: &str) -> String {
 
 // TODO(jig): consider use isize instead of i64 for integer type
 
+// Digits in `radix`, at least one.
+fn parse_integer(digits: &str, radix: u32) -> Option<MalBigInt> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    MalBigInt::from_str_radix(digits, radix).ok()
+}
+
+// Reads a number literal: an integer (decimal, `0x` hex, `0o` octal or
+// `0b` binary, optionally with an `N` suffix), a ratio such as `1/3`, or a
+// float such as `.5` or `1e6`, all with an optional sign and `_` between
+// digits; or `##Inf`, `##-Inf` and `##NaN`. `None` if `token` is not a
+// number, so that it is read as a symbol.
+pub(crate) fn parse_number(token: &str) -> Option<MalRet> {
+    match token {
+        "##Inf" => return Some(Ok(Float(MalFloat::INFINITY))),
+        "##-Inf" => return Some(Ok(Float(MalFloat::NEG_INFINITY))),
+        "##NaN" => return Some(Ok(Float(MalFloat::NAN))),
+        _ => {}
+    }
+    let (negative, unsigned) = match token.as_bytes().first()? {
+        b'-' => (true, &token[1..]),
+        b'+' => (false, &token[1..]),
+        _ => (false, token),
+    };
+    let starts_number = match unsigned.as_bytes() {
+        [c, ..] if c.is_ascii_digit() => true,
+        [b'.', c, ..] => c.is_ascii_digit(),
+        _ => false,
+    };
+    if !starts_number || unsigned.ends_with('_') || unsigned.contains("_.") || unsigned.contains("._") {
+        return None;
+    }
+    let body = unsigned.replace('_', "");
+    let sign = |n: MalBigInt| if negative { -n } else { n };
+
+    let radix = match body.get(..2) {
+        Some("0x" | "0X") => Some(16),
+        Some("0o" | "0O") => Some(8),
+        Some("0b" | "0B") => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        return parse_integer(&body[2..], radix).map(|n| Ok(bigint(sign(n))));
+    }
+    if body.bytes().all(|c| c.is_ascii_digit())
+        && let Ok(i) = body.parse::<i64>()
+    {
+        return Some(Ok(Int(if negative { -i } else { i })));
+    }
+    if let Some(n) = parse_integer(body.strip_suffix('N').unwrap_or(&body), 10) {
+        return Some(Ok(bigint(sign(n))));
+    }
+    if let Some((n, d)) = body.split_once('/') {
+        let (n, d) = (parse_integer(n, 10)?, parse_integer(d, 10)?);
+        if d == MalBigInt::ZERO {
+            return Some(syntax_error(&format!("zero denominator in {}", token)));
+        }
+        return Some(Ok(ratio(MalRatio::new(sign(n), d))));
+    }
+    if !body.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-')) {
+        return None;
+    }
+    let f = body.parse::<MalFloat>().ok()?;
+    Some(Ok(Float(if negative { -f } else { f })))
+}
+
+// Character named by the text after the `\` of a character literal: the
+// character itself, `newline`, `space`, `tab`, `return`, `backspace`,
+// `formfeed`, or `u` and a hex code point.
+fn parse_char(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    match name {
+        "newline" => Some('\n'),
+        "space" => Some(' '),
+        "tab" => Some('\t'),
+        "return" => Some('\r'),
+        "backspace" => Some('\u{8}'),
+        "formfeed" => Some('\u{c}'),
+        _ => char::from_u32(u32::from_str_radix(name.strip_prefix('u')?, 16).ok()?),
+    }
+}
+
 fn read_atom(rdr: &mut Reader) -> MalRet {
-    let token = rdr.next()?;
+    let token = rdr.next()?.text;
     match &token[..] {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
         _ => {
-            // Check if it's an integer (starts with optional - followed by digits)
-            if token.chars().all(|c| c.is_ascii_digit() || c == '-')
-                && token.parse::<i64>().is_ok() {
-                Ok(Int(token.parse::<i64>().unwrap()))
-            } else if token.chars().all(|c| c.is_ascii_digit() || c == '-' || c == '.')
-                && token.parse::<f32>().is_ok() {
-                Ok(Float(token.parse::<f32>().unwrap()))
+            if let Some(number) = parse_number(&token) {
+                number
             } else if token.starts_with('\"') && token.ends_with('\"') {
                 // String literal
                 Ok(Str(unescape_str(&token[1..token.len() - 1])))
             } else if token.starts_with('\"') {
-                error("INCOMPLETE:expected '\"', got EOF")
+                Err(MalErr::Incomplete("expected '\"', got EOF".to_string()))
+            } else if let Some(name) = token.strip_prefix('\\') {
+                match parse_char(name) {
+                    Some(c) => Ok(Char(c)),
+                    None => syntax_error(&format!("unsupported character {}", token)),
+                }
             } else if let Some(keyword) = token.strip_prefix(':') {
-                Ok(Kwd(String::from(keyword)))
+                Ok(Kwd(Symbol::intern(keyword)))
             } else {
-                Ok(Sym(token.to_string()))
+                Ok(Sym(Symbol::intern(&token)))
             }
         }
     }
 }
 
-fn read_seq(rdr: &mut Reader, end: &str) -> Result<Vec<MalVal>, MalVal> {
-    let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
-    loop {
-        let token = match rdr.peek() {
-            Ok(t) => t,
-            Err(_) => return error(&format!("INCOMPLETE:expected '{}', got EOF", end)),
-        };
-        if token == end {
-            break;
-        }
-        seq.push(read_form(rdr)?);
+// Compiles the string token of a `#"..."` regex literal. Backslashes are
+// passed to the regex as they are, not read as string escapes.
+fn read_regex(token: &str) -> MalRet {
+    if token.len() < 2 || !token.ends_with('"') {
+        return Err(MalErr::Incomplete("expected '\"', got EOF".to_string()));
     }
-    let _ = rdr.next();
-    Ok(seq)
+    #[cfg(feature = "regex")]
+    return Ok(MalVal::Regex(Rc::new(crate::regex::MalRegex::new(&token[1..token.len() - 1])?)));
+    #[cfg(not(feature = "regex"))]
+    syntax_error("regex literals need the `regex` feature")
+}
+
+// A form the reader has started but not finished.
+enum Open {
+    // A collection, by its opening bracket, with the elements read so far.
+    Seq { open: &'static str, items: Vec<MalVal>, start: Span },
+    // A reader macro wrapping the next form as `(sym form)`.
+    Wrap { sym: &'static str, start: Span },
+    // `^`, waiting for the metadata and then for the form it applies to.
+    Meta { meta: Option<MalVal>, start: Span },
 }
 
+fn closing(open: &str) -> &'static str {
+    match open {
+        "(" => ")",
+        "[" => "]",
+        _ => "}",
+    }
+}
+
+// Builds the collection opened by `open` once its closing bracket is read.
+fn read_seq_end(rdr: &Reader, open: &str, items: Vec<MalVal>, start: &Span) -> MalRet {
+    let meta = Rc::new(rdr.span_from(start).to_meta());
+    match open {
+        "(" => Ok(List(Rc::new(items), meta)),
+        "[" => Ok(Vector(items.into_iter().collect(), meta)),
+        "{" => match hash_map(items) {
+            Ok(Hash(hm, _)) => Ok(Hash(hm, meta)),
+            Ok(_) => unreachable!(),
+            Err(e) => syntax_error(&format!("{} at {}", e, rdr.located(start))),
+        },
+        _ => Ok(Set(items.into_iter().collect(), meta)),
+    }
+}
+
+// Reads one form. Unfinished forms are kept on a stack of their own rather
+// than read recursively, so deeply nested input cannot overflow the native
+// stack; it fails once it nests deeper than `MAX_NESTING`.
 fn read_form(rdr: &mut Reader) -> MalRet {
-    let token = rdr.peek()?;
-    match &token[..] {
-        "'" => {
-            let _ = rdr.next();
-            Ok(list!(Sym("quote".to_string()), read_form(rdr)?))
-        }
-        "`" => {
-            let _ = rdr.next();
-            Ok(list!(Sym("quasiquote".to_string()), read_form(rdr)?))
-        }
-        "~" => {
-            let _ = rdr.next();
-            Ok(list!(Sym("unquote".to_string()), read_form(rdr)?))
-        }
-        "~@" => {
-            let _ = rdr.next();
-            Ok(list!(Sym("splice-unquote".to_string()), read_form(rdr)?))
-        }
-        "^" => {
-            let _ = rdr.next();
-            let meta = read_form(rdr)?;
-            Ok(list!(Sym("with-meta".to_string()), read_form(rdr)?, meta))
-        }
-        "@" => {
-            let _ = rdr.next();
-            Ok(list!(Sym("deref".to_string()), read_form(rdr)?))
+    let mut open: Vec<Open> = vec![];
+    loop {
+        let token = match (rdr.peek(), open.last()) {
+            (Ok(t), _) => t,
+            (Err(_), Some(Open::Seq { open, .. })) => {
+                return Err(MalErr::Incomplete(format!("expected '{}', got EOF", closing(open))))
+            }
+            (Err(e), _) => return Err(e),
+        };
+        let start = token.span.clone();
+        let started = match &token.text[..] {
+            "'" => Some(Open::Wrap { sym: "quote", start }),
+            "`" => Some(Open::Wrap { sym: "quasiquote", start }),
+            "~" => Some(Open::Wrap { sym: "unquote", start }),
+            "~@" => Some(Open::Wrap { sym: "splice-unquote", start }),
+            "@" => Some(Open::Wrap { sym: "deref", start }),
+            "^" => Some(Open::Meta { meta: None, start }),
+            "(" => Some(Open::Seq { open: "(", items: vec![], start }),
+            "[" => Some(Open::Seq { open: "[", items: vec![], start }),
+            "{" => Some(Open::Seq { open: "{", items: vec![], start }),
+            "#{" => Some(Open::Seq { open: "#{", items: vec![], start }),
+            "#" if rdr.adjacent().is_some_and(|t| t.text == "{") => {
+                let _ = rdr.next();
+                Some(Open::Seq { open: "#{", items: vec![], start })
+            }
+            _ => None,
+        };
+        let mut form = match started {
+            Some(o) => {
+                if open.len() >= MAX_NESTING {
+                    return nesting_error();
+                }
+                let _ = rdr.next();
+                open.push(o);
+                continue;
+            }
+            None => match &token.text[..] {
+                end @ (")" | "]" | "}") => match open.pop() {
+                    Some(Open::Seq { open, items, start }) if closing(open) == end => {
+                        let _ = rdr.next();
+                        read_seq_end(rdr, open, items, &start)?
+                    }
+                    _ => return syntax_error(&format!("unexpected '{}' at {}", end, rdr.located(&token.span))),
+                },
+                // Brackets and quotes are tokens of their own, so `\(` is two tokens.
+                "\\" if rdr.adjacent().is_some_and(|t| t.text.chars().count() == 1) => {
+                    let _ = rdr.next();
+                    Char(rdr.next()?.text.chars().next().unwrap())
+                }
+                "#" if rdr.adjacent().is_some_and(|t| t.text.starts_with('"')) => {
+                    let _ = rdr.next();
+                    read_regex(&rdr.next()?.text)?
+                }
+                "#" => {
+                    let at = rdr.located(&token.span);
+                    return syntax_error(&format!("expected '{{' or '\"' right after '#' at {}", at));
+                }
+                _ => read_atom(rdr)?,
+            },
+        };
+        // Hands the finished form to the forms waiting for it.
+        loop {
+            match open.pop() {
+                None => return Ok(form),
+                Some(Open::Seq { open: o, mut items, start }) => {
+                    items.push(form);
+                    open.push(Open::Seq { open: o, items, start });
+                    break;
+                }
+                Some(Open::Meta { meta: None, start }) => {
+                    open.push(Open::Meta { meta: Some(form), start });
+                    break;
+                }
+                Some(Open::Meta { meta: Some(meta), start }) => {
+                    let l = vec![Sym(Symbol::intern("with-meta")), form, meta];
+                    form = List(Rc::new(l), Rc::new(rdr.span_from(&start).to_meta()));
+                }
+                Some(Open::Wrap { sym, start }) => {
+                    let l = vec![Sym(Symbol::intern(sym)), form];
+                    form = List(Rc::new(l), Rc::new(rdr.span_from(&start).to_meta()));
+                }
+            }
         }
-        ")" => error("unexpected ')'"),
-        "(" => Ok(list(read_seq(rdr, ")")?)),
-        "]" => error("unexpected ']'"),
-        "[" => Ok(vector(read_seq(rdr, "]")?)),
-        "}" => error("unexpected '}'"),
-        "{" => hash_map(read_seq(rdr, "}")?.to_vec()),
-        _ => read_atom(rdr),
     }
 }
 
 pub fn read_str(str: &str) -> MalRet {
-    let tokens = tokenize(str);
+    read_tokens(tokenize(str), None)
+}
+
+/// Like `read_str`, but records `file` in the span of every form read.
+pub fn read_str_named(str: &str, file: &str) -> MalRet {
+    read_tokens(tokenize(str), Some(file.to_string()))
+}
+
+fn read_tokens(tokens: Vec<Token>, file: Option<String>) -> MalRet {
     //println!("tokens: {:?}", tokens);
     if tokens.is_empty() {
-        return error("no input");
+        return syntax_error("no input");
     }
-    read_form(&mut Reader { pos: 0, tokens })
+    read_form(&mut Reader { pos: 0, tokens, file })
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::types::MalErr;
     use crate::types::MalVal::{Int, List, Str, Sym};
 
     #[test]
@@ -221,6 +517,33 @@ mod tests {
             Err(_) => panic!("rep() returned an error"),
         }
     }
+
+    #[test]
+    fn read_str_spans() {
+        let form = match super::read_str("; header\n(def! x\n  [1 2])") {
+            Ok(form) => form,
+            Err(e) => panic!("read_str() returned an error: {}", e),
+        };
+        let span = form.span().expect("list should carry a span");
+        assert_eq!((span.line, span.column), (2, 1));
+        assert_eq!((span.end_line, span.end_column), (3, 8));
+        match form {
+            List(lst, _) => {
+                let span = lst[2].span().expect("vector should carry a span");
+                assert_eq!((span.line, span.column), (3, 3));
+                assert_eq!((span.end_line, span.end_column), (3, 7));
+            }
+            _ => panic!("Expected List"),
+        }
+    }
+
+    #[test]
+    fn read_str_error_location() {
+        match super::read_str_named("\n  )", "config.lisp") {
+            Err(MalErr::Syntax(msg)) => assert_eq!(msg, "unexpected ')' at config.lisp:2:3"),
+            _ => panic!("Expected a located error"),
+        }
+    }
 }
 
 
@@ -298,7 +621,7 @@ where
 pub struct TokenStream<R: CharReader> {
     reader: R,
     buffer: String,
-    token_buffer: Vec<String>,
+    token_buffer: Vec<Token>,
     done: bool,
 }
 
@@ -330,7 +653,7 @@ impl<R: CharReader> TokenStream<R> {
 }
 
 impl<R: CharReader> Iterator for TokenStream<R> {
-    type Item = String;
+    type Item = Token;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Return from buffer first
@@ -351,7 +674,8 @@ impl<R: CharReader> Iterator for TokenStream<R> {
 /// Stream of MAL expressions
 pub struct MalStream<R: CharReader> {
     token_stream: TokenStream<R>,
-    token_buffer: Vec<String>,
+    token_buffer: Vec<Token>,
+    file: Option<String>,
 }
 
 impl<R: CharReader> MalStream<R> {
@@ -359,6 +683,15 @@ impl<R: CharReader> MalStream<R> {
         MalStream {
             token_stream: TokenStream::new(reader),
             token_buffer: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Creates a stream whose form spans and errors name `file`.
+    pub fn with_file(reader: R, file: &str) -> Self {
+        MalStream {
+            file: Some(file.to_string()),
+            ..MalStream::new(reader)
         }
     }
 }
@@ -374,6 +707,7 @@ impl<R: CharReader> Iterator for MalStream<R> {
                 let result = read_form(&mut Reader {
                     tokens: self.token_buffer.clone(),
                     pos: 0,
+                    file: self.file.clone(),
                 });
 
                 match result {
@@ -381,20 +715,13 @@ impl<R: CharReader> Iterator for MalStream<R> {
                         self.token_buffer.clear();
                         return Some(Ok(val));
                     }
+                    Err(MalErr::Incomplete(_)) => {
+                        // Need more tokens, continue reading
+                    }
                     Err(e) => {
-                        // Check if it's an incomplete error
-                        if let Str(ref msg) = e {
-                            if msg.starts_with("INCOMPLETE:") {
-                                // Need more tokens, continue reading
-                            } else {
-                                // Real error
-                                self.token_buffer.clear();
-                                return Some(Err(e));
-                            }
-                        } else {
-                            self.token_buffer.clear();
-                            return Some(Err(e));
-                        }
+                        // Real error
+                        self.token_buffer.clear();
+                        return Some(Err(e));
                     }
                 }
             }
@@ -411,6 +738,7 @@ impl<R: CharReader> Iterator for MalStream<R> {
                         let result = read_form(&mut Reader {
                             tokens: self.token_buffer.clone(),
                             pos: 0,
+                            file: self.file.clone(),
                         });
                         self.token_buffer.clear();
                         return Some(result);
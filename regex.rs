@@ -0,0 +1,125 @@
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use regex_automata::meta::Regex;
+use regex_automata::util::captures::Captures;
+use regex_automata::Input;
+
+use crate::types::MalVal::{Nil, Str};
+use crate::types::{func, list, type_error, vector, MalArgs, MalErr, MalRet, MalVal};
+
+/// A compiled regular expression, read from `#"..."` or made by
+/// `re-pattern`. Regexes are equal when their sources are.
+pub struct MalRegex {
+    source: String,
+    regex: Regex,
+    // The same pattern anchored at both ends, for `re-matches`.
+    whole: Regex,
+}
+
+impl MalRegex {
+    pub fn new(source: &str) -> Result<MalRegex, MalErr> {
+        let compile = |pattern: &str| {
+            Regex::new(pattern).map_err(|e| MalErr::Syntax(format!("invalid regex #\"{}\": {}", source, e)))
+        };
+        Ok(MalRegex {
+            source: source.to_string(),
+            regex: compile(source)?,
+            whole: compile(&format!(r"\A(?:{})\z", source))?,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Splits `s` around each match.
+    pub(crate) fn split(&self, s: &str) -> MalVal {
+        vector(self.regex.split(s).map(|span| Str(s[span].to_string())).collect())
+    }
+
+    /// Replaces each match in `s` with `replacement`: a string, where `$1`
+    /// or `${name}` stand for groups, or a function called with what
+    /// `re-find` would return for the match.
+    pub(crate) fn replace(&self, s: &str, replacement: &MalVal) -> MalRet {
+        let mut res = String::new();
+        let mut last = 0;
+        for caps in self.regex.captures_iter(s) {
+            let Some(m) = caps.get_match() else { continue };
+            res.push_str(&s[last..m.start()]);
+            match replacement {
+                Str(r) => res.push_str(&caps.interpolate_string(s, r)),
+                f => res.push_str(&f.apply(vec![groups(&caps, s)])?.pr_str(false)),
+            }
+            last = m.end();
+        }
+        res.push_str(&s[last..]);
+        Ok(Str(res))
+    }
+}
+
+// The matched text if the regex has no groups, or else a vector of it and
+// each group, with nil for groups that did not take part in the match.
+fn groups(caps: &Captures, s: &str) -> MalVal {
+    let text = |i| caps.get_group(i).map_or(Nil, |span| Str(s[span].to_string()));
+    match caps.group_len() {
+        1 => text(0),
+        n => vector((0..n).map(text).collect()),
+    }
+}
+
+fn search(regex: &Regex, s: &str) -> MalVal {
+    let mut caps = regex.create_captures();
+    regex.search_captures(&Input::new(s), &mut caps);
+    match caps.is_match() {
+        true => groups(&caps, s),
+        false => Nil,
+    }
+}
+
+fn args<'a>(a: &'a MalArgs, name: &str) -> Result<(&'a MalRegex, &'a str), MalErr> {
+    match (a.first(), a.get(1)) {
+        (Some(MalVal::Regex(re)), Some(Str(s))) => Ok((re, s)),
+        _ => Err(MalErr::Type(format!("{}: expecting (regex, str) args", name))),
+    }
+}
+
+fn re_pattern(a: MalArgs) -> MalRet {
+    match a[0] {
+        MalVal::Regex(_) => Ok(a[0].clone()),
+        Str(ref s) => Ok(MalVal::Regex(Rc::new(MalRegex::new(s)?))),
+        _ => type_error("re-pattern: expecting a string"),
+    }
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    let (re, s) = args(&a, "re-find")?;
+    Ok(search(&re.regex, s))
+}
+
+fn re_matches(a: MalArgs) -> MalRet {
+    let (re, s) = args(&a, "re-matches")?;
+    Ok(search(&re.whole, s))
+}
+
+fn re_seq(a: MalArgs) -> MalRet {
+    let (re, s) = args(&a, "re-seq")?;
+    let found: Vec<MalVal> = re.regex.captures_iter(s).map(|caps| groups(&caps, s)).collect();
+    match found.is_empty() {
+        true => Ok(Nil),
+        false => Ok(list(found)),
+    }
+}
+
+pub fn ns() -> Vec<(&'static str, MalVal)> {
+    vec![
+        ("regex?", func(|a| Ok(MalVal::Bool(matches!(a[0], MalVal::Regex(_)))))),
+        ("re-pattern", func(re_pattern)),
+        ("re-find", func(re_find)),
+        ("re-matches", func(re_matches)),
+        ("re-seq", func(re_seq)),
+    ]
+}
@@ -0,0 +1,192 @@
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Deref;
+#[cfg(target_has_atomic = "8")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use hashbrown::HashMap;
+
+/// Interned name of a symbol or keyword.
+///
+/// Every distinct name is stored once for the life of the program, so a
+/// `Symbol` is a copyable reference, equality is pointer comparison and the
+/// hash is computed once, when the name is interned.
+///
+/// The table is global to the process and guarded by a spinlock, and it
+/// never shrinks: every name ever read or created, including through
+/// `symbol`, `keyword` or `read-string` on untrusted input, stays allocated
+/// until the program exits. Hosts evaluating unbounded untrusted input
+/// should bound the number of distinct names it may create.
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static Entry);
+
+/// Special forms, told apart by the interned symbol alone.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum SpecialForm {
+    Def,
+    DefMacro,
+    Let,
+    Do,
+    If,
+    Fn,
+    Quote,
+    Quasiquote,
+    Unquote,
+    SpliceUnquote,
+    Try,
+}
+
+struct Entry {
+    name: &'static str,
+    // FNV-1a of `name`, so that hash-map order does not change from run to
+    // run the way it would hashing the address.
+    hash: u64,
+    special: Option<SpecialForm>,
+}
+
+const fn fnv1a(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+impl Entry {
+    const fn special(name: &'static str, form: SpecialForm) -> Entry {
+        Entry { name, hash: fnv1a(name), special: Some(form) }
+    }
+}
+
+// Interned before any other name, so that their symbols carry the form.
+static SPECIAL_FORMS: [Entry; 11] = [
+    Entry::special("def!", SpecialForm::Def),
+    Entry::special("defmacro!", SpecialForm::DefMacro),
+    Entry::special("let*", SpecialForm::Let),
+    Entry::special("do", SpecialForm::Do),
+    Entry::special("if", SpecialForm::If),
+    Entry::special("fn*", SpecialForm::Fn),
+    Entry::special("quote", SpecialForm::Quote),
+    Entry::special("quasiquote", SpecialForm::Quasiquote),
+    Entry::special("unquote", SpecialForm::Unquote),
+    Entry::special("splice-unquote", SpecialForm::SpliceUnquote),
+    Entry::special("try*", SpecialForm::Try),
+];
+
+type Names = HashMap<&'static str, &'static Entry, fnv::FnvBuildHasher>;
+
+struct Interner {
+    #[cfg(target_has_atomic = "8")]
+    locked: AtomicBool,
+    names: UnsafeCell<Option<Names>>,
+}
+
+// SAFETY: `names` is only accessed inside `with_names`, which holds the
+// spinlock. Targets without atomic compare-and-swap are single-core
+// microcontrollers without threads, where interning must simply not be
+// done from interrupt handlers.
+unsafe impl Sync for Interner {}
+
+static INTERNER: Interner = Interner {
+    #[cfg(target_has_atomic = "8")]
+    locked: AtomicBool::new(false),
+    names: UnsafeCell::new(None),
+};
+
+fn with_names<R>(f: impl FnOnce(&mut Names) -> R) -> R {
+    #[cfg(target_has_atomic = "8")]
+    while INTERNER
+        .locked
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    // SAFETY: see `impl Sync for Interner`.
+    let names = unsafe { &mut *INTERNER.names.get() };
+    let res = f(names.get_or_insert_with(|| SPECIAL_FORMS.iter().map(|e| (e.name, e)).collect()));
+    #[cfg(target_has_atomic = "8")]
+    INTERNER.locked.store(false, Ordering::Release);
+    res
+}
+
+impl Symbol {
+    /// Returns the symbol named `name`, adding it to the table if needed.
+    pub fn intern(name: &str) -> Symbol {
+        with_names(|names| match names.get(name) {
+            Some(e) => Symbol(e),
+            None => {
+                let name: &'static str = Box::leak(name.into());
+                let e: &'static Entry = Box::leak(Box::new(Entry { name, hash: fnv1a(name), special: None }));
+                names.insert(name, e);
+                Symbol(e)
+            }
+        })
+    }
+
+    /// Returns the symbol named `name` if it has been interned.
+    pub fn find(name: &str) -> Option<Symbol> {
+        with_names(|names| names.get(name).map(|e| Symbol(e)))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0.name
+    }
+
+    /// The special form this symbol names, if any.
+    pub(crate) fn special(&self) -> Option<SpecialForm> {
+        self.0.special
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        core::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.hash)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.0.name == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.0.name == *other
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0.name
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0.name)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0.name)
+    }
+}
@@ -1,66 +1,653 @@
 extern crate alloc;
-use alloc::string::String;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use itertools::Itertools;
+
+use crate::symbol::Symbol;
+use crate::types::MalVal::{Bool, Hash, Int, Kwd, Nil, Str};
+use crate::printer::pr_seq;
+use crate::types::{arity_error, func_closure, new_map, type_error, vector, MalArgs, MalErr, MalVal};
+
+/// What kind of failure a filesystem operation hit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// The host has no filesystem, or does not support this operation.
+    Unsupported,
+    Other,
+}
+
+impl FsErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsErrorKind::NotFound => "not found",
+            FsErrorKind::PermissionDenied => "permission denied",
+            FsErrorKind::AlreadyExists => "already exists",
+            FsErrorKind::NotADirectory => "not a directory",
+            FsErrorKind::IsADirectory => "is a directory",
+            FsErrorKind::DirectoryNotEmpty => "directory not empty",
+            FsErrorKind::Unsupported => "not supported",
+            FsErrorKind::Other => "filesystem error",
+        }
+    }
+}
+
+/// A failed filesystem operation, raised as `MalErr::Fs`. Lisp code
+/// catches it as a map `{:kind :not-found :path "..." :message "..."}`, the
+/// kind being its `FsErrorKind` with dashes for spaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FsError {
+    pub kind: FsErrorKind,
+    pub path: String,
+    /// Host-specific detail, such as the OS error message.
+    pub message: String,
+}
+
+impl FsError {
+    /// An error on `path` described by its kind alone.
+    pub fn new(kind: FsErrorKind, path: &str) -> FsError {
+        FsError { kind, path: path.to_string(), message: kind.as_str().to_string() }
+    }
+}
+
+impl FsError {
+    /// The map `catch*` binds for this error.
+    pub fn to_value(&self) -> MalVal {
+        let kind = self.kind.as_str().replace(' ', "-");
+        let mut hm = new_map();
+        hm.insert_mut(Kwd(Symbol::intern("kind")), Kwd(Symbol::intern(&kind)));
+        hm.insert_mut(Kwd(Symbol::intern("path")), Str(self.path.clone()));
+        hm.insert_mut(Kwd(Symbol::intern("message")), Str(self.message.clone()));
+        Hash(hm, Rc::new(Nil))
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl From<FsError> for MalErr {
+    fn from(e: FsError) -> MalErr {
+        MalErr::Fs(e)
+    }
+}
 
 /// System operations trait for dependency injection
 /// Allows different implementations for std, embedded (Raspberry Pi Pico), etc.
 pub trait SystemOps {
     /// Read a file and return its contents as a String
-    /// Used by the `slurp` function
-    fn read_file(&self, path: &str) -> Result<String, String>;
+    /// Used by the `slurp` and `load-file` functions
+    fn read_file(&self, path: &str) -> Result<String, FsError>;
+
+    /// Create or truncate a file and write `contents` to it
+    /// Used by the `spit` function
+    fn write_file(&self, path: &str, _contents: &str) -> Result<(), FsError> {
+        Err(FsError::new(FsErrorKind::Unsupported, path))
+    }
+
+    /// Append `contents` to a file, creating it if needed
+    /// Used by the `append-file` function
+    fn append_file(&self, path: &str, _contents: &str) -> Result<(), FsError> {
+        Err(FsError::new(FsErrorKind::Unsupported, path))
+    }
+
+    /// Whether a file or directory exists at `path`
+    /// Used by the `file-exists?` function
+    fn file_exists(&self, path: &str) -> Result<bool, FsError> {
+        Err(FsError::new(FsErrorKind::Unsupported, path))
+    }
+
+    /// The names of the entries of a directory, sorted
+    /// Used by the `list-dir` function
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FsError> {
+        Err(FsError::new(FsErrorKind::Unsupported, path))
+    }
+
+    /// Delete a file or an empty directory
+    /// Used by the `delete-file` function
+    fn delete_file(&self, path: &str) -> Result<(), FsError> {
+        Err(FsError::new(FsErrorKind::Unsupported, path))
+    }
+
+    /// Create a directory and any missing parents
+    /// Used by the `mkdir` function
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        Err(FsError::new(FsErrorKind::Unsupported, path))
+    }
 
     /// Get current time in milliseconds since UNIX epoch
-    /// Used by the `time/ms` function
+    /// Used by the `time/ms` and `time/s` functions
     fn time_ms(&self) -> Result<i64, String>;
 
+    /// Get current time in nanoseconds since UNIX epoch, the same reference
+    /// as `time_ms`, so every `time/*` function agrees
+    /// Used by the `time/ns` and `time/us` functions; defaults to `time_ms`
+    fn time_ns(&self) -> Result<i64, String> {
+        self.time_ms().map(|ms| ms.saturating_mul(1_000_000))
+    }
+
     /// Read a line from user input with a prompt
     /// Used by the `readline` function
     fn readline(&self, prompt: &str) -> Option<String>;
+
+    /// Write text to the output (console, UART, display...)
+    /// Used by the `pr`, `prn`, `print`, `println` and `newline` functions
+    fn write_str(&self, s: &str) -> Result<(), String>;
+
+    /// Flush any buffered output
+    /// Used after each line of output and by the `flush` function
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Where the printing functions write: the innermost `with-out-str` buffer,
+// or else the host.
+struct Output {
+    sys: Rc<dyn SystemOps>,
+    captures: RefCell<Vec<String>>,
+}
+
+impl Output {
+    fn write(&self, s: &str) -> Result<(), MalErr> {
+        match self.captures.borrow_mut().last_mut() {
+            Some(buf) => {
+                buf.push_str(s);
+                Ok(())
+            }
+            None => self.sys.write_str(s).map_err(MalErr::Io),
+        }
+    }
+
+    fn flush(&self) -> Result<(), MalErr> {
+        match self.captures.borrow().is_empty() {
+            true => self.sys.flush().map_err(MalErr::Io),
+            false => Ok(()),
+        }
+    }
+}
+
+fn no_args(a: &MalArgs) -> Result<(), MalErr> {
+    match a.is_empty() {
+        true => Ok(()),
+        false => arity_error("expecting no args"),
+    }
+}
+
+fn time(sys: &Rc<dyn SystemOps>, ns: bool, div: i64) -> MalVal {
+    let sys = sys.clone();
+    func_closure(move |a| {
+        no_args(&a)?;
+        let t = match ns {
+            true => sys.time_ns(),
+            false => sys.time_ms(),
+        };
+        t.map(|t| Int(t / div)).map_err(MalErr::Io)
+    })
+}
+
+// A builtin taking a path, calling `op` on it and `ret` on the result.
+fn file_op<T: 'static>(
+    sys: &Rc<dyn SystemOps>,
+    name: &'static str,
+    op: fn(&dyn SystemOps, &str) -> Result<T, FsError>,
+    ret: fn(T) -> MalVal,
+) -> MalVal {
+    let sys = sys.clone();
+    func_closure(move |a| match a.as_slice() {
+        [Str(path)] => Ok(ret(op(&*sys, path)?)),
+        _ => type_error(&format!("{}: expecting (str) arg", name)),
+    })
+}
+
+// A builtin taking a path and contents, which are printed like `str` does.
+fn write_op(
+    sys: &Rc<dyn SystemOps>,
+    name: &'static str,
+    op: fn(&dyn SystemOps, &str, &str) -> Result<(), FsError>,
+) -> MalVal {
+    let sys = sys.clone();
+    func_closure(move |a| match a.as_slice() {
+        [Str(path), contents] => {
+            op(&*sys, path, &contents.pr_str(false))?;
+            Ok(Nil)
+        }
+        _ => type_error(&format!("{}: expecting (str, contents) args", name)),
+    })
+}
+
+fn readline(sys: &Rc<dyn SystemOps>) -> MalVal {
+    let sys = sys.clone();
+    func_closure(move |a| match a.first() {
+        Some(Str(prompt)) if a.len() == 1 => Ok(sys.readline(prompt).map_or(Nil, Str)),
+        _ => type_error("readline: expecting (str) arg"),
+    })
+}
+
+fn printer(out: &Rc<Output>, print_readably: bool, end: &'static str) -> MalVal {
+    let out = out.clone();
+    func_closure(move |a| {
+        out.write(&pr_seq(&a, print_readably, "", end, " "))?;
+        if !end.is_empty() {
+            out.flush()?;
+        }
+        Ok(Nil)
+    })
+}
+
+fn newline(out: &Rc<Output>) -> MalVal {
+    let out = out.clone();
+    func_closure(move |a| {
+        no_args(&a)?;
+        out.write("\n")?;
+        out.flush()?;
+        Ok(Nil)
+    })
+}
+
+fn flush(out: &Rc<Output>) -> MalVal {
+    let out = out.clone();
+    func_closure(move |a| {
+        no_args(&a)?;
+        out.flush()?;
+        Ok(Nil)
+    })
+}
+
+// Calls a function of no args, returning what it printed instead of its
+// value. `with-out-str` wraps its body in such a function.
+fn with_out_str(out: &Rc<Output>) -> MalVal {
+    let out = out.clone();
+    func_closure(move |a| {
+        if a.len() != 1 {
+            return arity_error("with-out-str*: expecting exactly 1 arg");
+        }
+        out.captures.borrow_mut().push(String::new());
+        let res = a[0].apply(vec![]);
+        let captured = out.captures.borrow_mut().pop().unwrap_or_default();
+        res.map(|_| Str(captured))
+    })
+}
+
+/// The builtins backed by `sys`, registered by `mal_env_with_system`.
+pub fn ns(sys: Rc<dyn SystemOps>) -> Vec<(&'static str, MalVal)> {
+    let out = Rc::new(Output { sys: sys.clone(), captures: RefCell::new(Vec::new()) });
+    vec![
+        ("pr", printer(&out, true, "")),
+        ("prn", printer(&out, true, "\n")),
+        ("print", printer(&out, false, "")),
+        ("println", printer(&out, false, "\n")),
+        ("newline", newline(&out)),
+        ("flush", flush(&out)),
+        ("with-out-str*", with_out_str(&out)),
+        ("slurp", file_op(&sys, "slurp", |s, p| s.read_file(p), Str)),
+        ("spit", write_op(&sys, "spit", |s, p, c| s.write_file(p, c))),
+        ("append-file", write_op(&sys, "append-file", |s, p, c| s.append_file(p, c))),
+        ("file-exists?", file_op(&sys, "file-exists?", |s, p| s.file_exists(p), Bool)),
+        ("list-dir", file_op(&sys, "list-dir", |s, p| s.list_dir(p), |names| vector(names.into_iter().map(Str).collect()))),
+        ("delete-file", file_op(&sys, "delete-file", |s, p| s.delete_file(p), |_| Nil)),
+        ("mkdir", file_op(&sys, "mkdir", |s, p| s.mkdir(p), |_| Nil)),
+        ("readline", readline(&sys)),
+        ("time/ns", time(&sys, true, 1)),
+        ("time/us", time(&sys, true, 1_000)),
+        ("time/ms", time(&sys, false, 1)),
+        ("time/s", time(&sys, false, 1_000)),
+    ]
+}
+
+/// `SystemOps` kept entirely in memory, for tests and sandboxed runs:
+/// files live in a map of paths to contents, `readline` takes queued lines,
+/// output is collected into a string and the clock only moves when set.
+///
+/// Paths are `/`-separated; `.`, `..` and repeated slashes are resolved.
+/// Writing a file creates its missing parent directories.
+///
+/// ```
+/// use std::rc::Rc;
+/// use mal::{mal_env_with_system, rep, MemSystemOps};
+///
+/// let sys = Rc::new(MemSystemOps::new().with_file("lib.mal", "(def! x 1)"));
+/// let env = mal_env_with_system(sys.clone());
+/// rep("(spit \"out.txt\" (slurp \"lib.mal\"))", &env).unwrap();
+/// assert_eq!(sys.file("out.txt").as_deref(), Some("(def! x 1)"));
+/// ```
+#[derive(Default)]
+pub struct MemSystemOps {
+    files: RefCell<BTreeMap<String, String>>,
+    dirs: RefCell<BTreeSet<String>>,
+    input: RefCell<VecDeque<String>>,
+    output: RefCell<String>,
+    time_ms: Cell<i64>,
 }
 
-// Example implementation for std environments - users should copy this
-// and adapt it to their needs:
-//
-// ```
-// extern crate alloc;
-// use alloc::string::String;
-// use mal::SystemOps;
-//
-// pub struct StdSystemOps {
-//     readline_fn: Option<fn(&str) -> Option<String>>,
-// }
-//
-// impl StdSystemOps {
-//     pub fn new(readline_fn: Option<fn(&str) -> Option<String>>) -> Self {
-//         StdSystemOps { readline_fn }
-//     }
-// }
-//
-// impl SystemOps for StdSystemOps {
-//     fn read_file(&self, path: &str) -> Result<String, String> {
-//         use std::fs::File;
-//         use std::io::Read;
-//
-//         let mut s = String::new();
-//         File::open(path)
-//             .and_then(|mut f| f.read_to_string(&mut s))
-//             .map(|_| s)
-//             .map_err(|e| e.to_string())
-//     }
-//
-//     fn time_ms(&self) -> Result<i64, String> {
-//         use std::time::{SystemTime, UNIX_EPOCH};
-//
-//         SystemTime::now()
-//             .duration_since(UNIX_EPOCH)
-//             .map(|d| d.as_secs() as i64 * 1000 + d.subsec_nanos() as i64 / 1_000_000)
-//             .map_err(|e| alloc::format!("{:?}", e))
-//     }
-//
-//     fn readline(&self, prompt: &str) -> Option<String> {
-//         match self.readline_fn {
-//             Some(f) => f(prompt),
-//             None => None,
-//         }
-//     }
-// }
-// ```
+// `a/./b/`, `a//b` and `a/c/../b` all name `a/b`; a leading `/` is kept.
+// The relative root is `""`.
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    match path.starts_with('/') {
+        true => format!("/{}", parts.join("/")),
+        false => parts.join("/"),
+    }
+}
+
+// The directory holding a normalized path, or `None` for the roots.
+fn parent(path: &str) -> Option<&str> {
+    match path.rsplit_once('/') {
+        _ if path.is_empty() || path == "/" => None,
+        Some(("", _)) => Some("/"),
+        Some((dir, _)) => Some(dir),
+        None => Some(""),
+    }
+}
+
+impl MemSystemOps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file, creating its parent directories. Panics if `path` is a
+    /// directory or lies under a file.
+    pub fn with_file(self, path: &str, contents: &str) -> Self {
+        if let Err(e) = self.write(path, contents, false) {
+            panic!("MemSystemOps::with_file: {}", e);
+        }
+        self
+    }
+
+    /// Queues a line for `readline`; once the queue is empty it returns nil.
+    pub fn with_input(self, line: &str) -> Self {
+        self.input.borrow_mut().push_back(line.to_string());
+        self
+    }
+
+    /// Sets the time `time/ms` and friends report from now on.
+    pub fn set_time_ms(&self, ms: i64) {
+        self.time_ms.set(ms);
+    }
+
+    /// The contents of the file at `path`, if there is one.
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.files.borrow().get(&normalize(path)).cloned()
+    }
+
+    /// The normalized paths of all files, sorted.
+    pub fn paths(&self) -> Vec<String> {
+        self.files.borrow().keys().cloned().collect()
+    }
+
+    /// Everything printed so far.
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        parent(path).is_none() || self.dirs.borrow().contains(path)
+    }
+
+    // Creates the missing directories above `path`, failing if one of them
+    // is a file.
+    fn make_parents(&self, path: &str, shown: &str) -> Result<(), FsError> {
+        let mut dir = parent(path);
+        while let Some(d) = dir.filter(|d| parent(d).is_some()) {
+            if self.files.borrow().contains_key(d) {
+                return Err(FsError::new(FsErrorKind::NotADirectory, shown));
+            }
+            self.dirs.borrow_mut().insert(d.to_string());
+            dir = parent(d);
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &str, contents: &str, append: bool) -> Result<(), FsError> {
+        let p = normalize(path);
+        if self.is_dir(&p) {
+            return Err(FsError::new(FsErrorKind::IsADirectory, path));
+        }
+        self.make_parents(&p, path)?;
+        let mut files = self.files.borrow_mut();
+        let file = files.entry(p).or_default();
+        if !append {
+            file.clear();
+        }
+        file.push_str(contents);
+        Ok(())
+    }
+}
+
+impl SystemOps for MemSystemOps {
+    fn read_file(&self, path: &str) -> Result<String, FsError> {
+        let p = normalize(path);
+        match self.files.borrow().get(&p) {
+            Some(contents) => Ok(contents.clone()),
+            None if self.is_dir(&p) => Err(FsError::new(FsErrorKind::IsADirectory, path)),
+            None => Err(FsError::new(FsErrorKind::NotFound, path)),
+        }
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> Result<(), FsError> {
+        self.write(path, contents, false)
+    }
+
+    fn append_file(&self, path: &str, contents: &str) -> Result<(), FsError> {
+        self.write(path, contents, true)
+    }
+
+    fn file_exists(&self, path: &str) -> Result<bool, FsError> {
+        let p = normalize(path);
+        Ok(self.files.borrow().contains_key(&p) || self.is_dir(&p))
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FsError> {
+        let p = normalize(path);
+        if !self.is_dir(&p) {
+            let kind = match self.files.borrow().contains_key(&p) {
+                true => FsErrorKind::NotADirectory,
+                false => FsErrorKind::NotFound,
+            };
+            return Err(FsError::new(kind, path));
+        }
+        let files = self.files.borrow();
+        let dirs = self.dirs.borrow();
+        let names = files
+            .keys()
+            .chain(dirs.iter())
+            .filter(|entry| parent(entry) == Some(p.as_str()))
+            .map(|entry| entry.rsplit('/').next().unwrap_or(entry).to_string())
+            .sorted()
+            .collect();
+        Ok(names)
+    }
+
+    fn delete_file(&self, path: &str) -> Result<(), FsError> {
+        let p = normalize(path);
+        if self.files.borrow_mut().remove(&p).is_some() {
+            return Ok(());
+        }
+        if !self.is_dir(&p) {
+            return Err(FsError::new(FsErrorKind::NotFound, path));
+        }
+        if !self.list_dir(path)?.is_empty() {
+            return Err(FsError::new(FsErrorKind::DirectoryNotEmpty, path));
+        }
+        match self.dirs.borrow_mut().remove(&p) {
+            true => Ok(()),
+            false => Err(FsError::new(FsErrorKind::PermissionDenied, path)),
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        let p = normalize(path);
+        if self.files.borrow().contains_key(&p) {
+            return Err(FsError::new(FsErrorKind::AlreadyExists, path));
+        }
+        self.make_parents(&p, path)?;
+        if parent(&p).is_some() {
+            self.dirs.borrow_mut().insert(p);
+        }
+        Ok(())
+    }
+
+    fn time_ms(&self) -> Result<i64, String> {
+        Ok(self.time_ms.get())
+    }
+
+    fn readline(&self, _prompt: &str) -> Option<String> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn write_str(&self, s: &str) -> Result<(), String> {
+        self.output.borrow_mut().push_str(s);
+        Ok(())
+    }
+}
+
+/// `SystemOps` for hosts with an operating system: files through
+/// `std::fs`, output to stdout and lines from stdin or a line editor.
+#[cfg(feature = "std")]
+pub struct StdSystemOps {
+    readline_fn: Option<fn(&str) -> Option<String>>,
+}
+
+#[cfg(feature = "std")]
+impl StdSystemOps {
+    /// `readline_fn` reads lines for `readline`, e.g. through a line editor;
+    /// without it, the prompt goes to stdout and the line comes from stdin.
+    pub fn new(readline_fn: Option<fn(&str) -> Option<String>>) -> Self {
+        StdSystemOps { readline_fn }
+    }
+}
+
+#[cfg(feature = "std")]
+fn since_epoch() -> Result<std::time::Duration, String> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "std")]
+fn fs_error(path: &str, e: std::io::Error) -> FsError {
+    use std::io::ErrorKind;
+
+    let kind = match e.kind() {
+        ErrorKind::NotFound => FsErrorKind::NotFound,
+        ErrorKind::PermissionDenied => FsErrorKind::PermissionDenied,
+        ErrorKind::AlreadyExists => FsErrorKind::AlreadyExists,
+        ErrorKind::NotADirectory => FsErrorKind::NotADirectory,
+        ErrorKind::IsADirectory => FsErrorKind::IsADirectory,
+        ErrorKind::DirectoryNotEmpty => FsErrorKind::DirectoryNotEmpty,
+        ErrorKind::Unsupported => FsErrorKind::Unsupported,
+        _ => FsErrorKind::Other,
+    };
+    FsError { kind, path: path.to_string(), message: e.to_string() }
+}
+
+#[cfg(feature = "std")]
+impl SystemOps for StdSystemOps {
+    fn read_file(&self, path: &str) -> Result<String, FsError> {
+        std::fs::read_to_string(path).map_err(|e| fs_error(path, e))
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> Result<(), FsError> {
+        std::fs::write(path, contents).map_err(|e| fs_error(path, e))
+    }
+
+    fn append_file(&self, path: &str, contents: &str) -> Result<(), FsError> {
+        use std::io::Write;
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(contents.as_bytes()))
+            .map_err(|e| fs_error(path, e))
+    }
+
+    fn file_exists(&self, path: &str) -> Result<bool, FsError> {
+        std::path::Path::new(path).try_exists().map_err(|e| fs_error(path, e))
+    }
+
+    fn list_dir(&self, path: &str) -> Result<Vec<String>, FsError> {
+        let mut names = std::fs::read_dir(path)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| fs_error(path, e))?;
+        names.sort();
+        Ok(names)
+    }
+
+    fn delete_file(&self, path: &str) -> Result<(), FsError> {
+        match std::fs::symlink_metadata(path) {
+            Ok(m) if m.is_dir() => std::fs::remove_dir(path),
+            _ => std::fs::remove_file(path),
+        }
+        .map_err(|e| fs_error(path, e))
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        std::fs::create_dir_all(path).map_err(|e| fs_error(path, e))
+    }
+
+    fn time_ms(&self) -> Result<i64, String> {
+        since_epoch().map(|d| d.as_millis() as i64)
+    }
+
+    fn time_ns(&self) -> Result<i64, String> {
+        since_epoch().map(|d| d.as_nanos() as i64)
+    }
+
+    fn readline(&self, prompt: &str) -> Option<String> {
+        if let Some(f) = self.readline_fn {
+            return f(prompt);
+        }
+        self.write_str(prompt).ok()?;
+        self.flush().ok()?;
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                while line.ends_with('\n') || line.ends_with('\r') {
+                    line.pop();
+                }
+                Some(line)
+            }
+        }
+    }
+
+    fn write_str(&self, s: &str) -> Result<(), String> {
+        use std::io::Write;
+
+        std::io::stdout().write_all(s.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        use std::io::Write;
+
+        std::io::stdout().flush().map_err(|e| e.to_string())
+    }
+}
@@ -1,4 +1,24 @@
-use mal::{initialize_mal_env, mal_env, rep};
+use mal::{initialize_mal_env, mal_env, rep, Env, MalErr};
+
+// Evaluates each form, comparing its printed value with the expected one.
+fn check(env: &Env, cases: &[(&str, &str)]) {
+    for (code, expected) in cases {
+        match rep(code, env) {
+            Ok(s) => assert_eq!(&s, expected, "{}", code),
+            Err(e) => panic!("rep() returned an error for {}: {}", code, e),
+        }
+    }
+}
+
+// Evaluates each form, expecting an error that `is_expected` accepts.
+fn check_err(env: &Env, codes: &[&str], is_expected: fn(&MalErr) -> bool) {
+    for code in codes {
+        match rep(code, env) {
+            Err(e) => assert!(is_expected(e.kind()), "{}: {}", code, e),
+            Ok(s) => panic!("Should have returned an error for {}, but got: {}", code, s),
+        }
+    }
+}
 
 #[test]
 fn reader_unfinished_expr() {
@@ -7,11 +27,9 @@ fn reader_unfinished_expr() {
     match rep("(+ 1", &env) {
         Ok(s) => panic!("Should have returned an error, but got: {}", s),
         Err(e) => {
-            let err_msg = e.pr_str(false);
-            if err_msg == "INCOMPLETE:expected ')', got EOF" {
-                ()
-            } else {
-                panic!("Unexpected error message: {}", err_msg);
+            match e {
+                MalErr::Incomplete(msg) => assert_eq!(msg, "expected ')', got EOF"),
+                _ => panic!("Unexpected error: {:?}", e),
             }
         },
     }
@@ -24,11 +42,9 @@ fn reader_unfinished_string() {
     match rep("\"hello", &env) {
         Ok(s) => panic!("Should have returned an error, but got: {}", s),
         Err(e) => {
-            let err_msg = e.pr_str(false);
-            if err_msg == "INCOMPLETE:expected '\"', got EOF" {
-                ()
-            } else {
-                panic!("Unexpected error message: {}", err_msg);
+            match e {
+                MalErr::Incomplete(msg) => assert_eq!(msg, "expected '\"', got EOF"),
+                _ => panic!("Unexpected error: {:?}", e),
             }
         },
     }
@@ -49,10 +65,10 @@ fn reader_multiple_expressions() {
             }
         },
         Err(e) => {
-            if e.pr_str(false) == "unexpected tokens after first expression" {
+            if e.to_string() == "unexpected tokens after first expression" {
                 ()
             } else {
-                panic!("Unexpected error message: {}", e.pr_str(false));
+                panic!("Unexpected error message: {}", e);
             }
         },
     }
@@ -169,166 +185,840 @@ fn eval_read_string() {
     }
 }
 
-use mal::types::{MalArgs, MalRet, error, func};
-use mal::types::MalVal::{Int,  Str, Nil};
-use std::sync::OnceLock;
-use std::io::{self, Write};
+#[test]
+fn eval_eval() {
+    let env = mal::mal_env_with_system(std::rc::Rc::new(mal::MemSystemOps::new()));
+    initialize_mal_env(&env, vec![]);
 
-fn slurp(a: MalArgs) -> MalRet {
-    if a.len() != 1 {
-        return error("read-file expects 1 argument");
+    match rep("(eval (read-string \"(+ 1 10)\"))", &env) {
+        Ok(s) => assert_eq!(s, "11"),
+        Err(_) => panic!("rep() returned an error"),
     }
-    match a[0] {
-        Str(ref s) => {
-            match std::fs::read_to_string(s).map_err(|_| error("failed to read file")) {
-                Err(e) => {e}
-                Ok(content) => {Ok(Str(content))}
-            }
-        }
-        _ => error("file name must be string"),
+}
+
+#[test]
+fn eval_fn() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    match rep("((fn* (a b) (+ a b)) 5 7)", &env) {
+        Ok(s) => assert_eq!(s, "12"),
+        Err(_) => panic!("rep() returned an error"),
     }
+
 }
 
-static BOOT_TIME: OnceLock<std::time::Instant> = OnceLock::new();
+#[test]
+fn eval_fn_insuficient_args() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
 
-fn time_ns(a: MalArgs) -> MalRet {
-    if a.len() != 0 {
-        return error("time/ns/us/ms/s expect 0 arguments");
+    // Should error because function expects 2 arguments but got 0
+    match rep("((fn* (a b) (+ a b)))", &env) {
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
+        Err(_) => (), // Expected error
     }
-    let boot = BOOT_TIME.get().unwrap();
-    let elapsed = boot.elapsed();
-    let ns = elapsed.as_secs() as i64 * 1_000_000_000 + elapsed.subsec_nanos() as i64;
-    Ok(Int(ns))
 }
 
-fn time_us(a: MalArgs) -> MalRet {
-    let Int(ns) = time_ns(a)? else { unreachable!() };
-    Ok(Int(ns / 1_000i64))
+#[test]
+fn eval_fn_too_many_args() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    // Should error because function expects 2 arguments but got 0
+    match rep("((fn* (a b) (+ a b)) 1 2 3)", &env) {
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
+        Err(_) => (), // Expected error
+    }
 }
+#[test]
+fn error_kinds() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
 
-fn time_ms(a: MalArgs) -> MalRet {
-    let Int(ns) = time_ns(a)? else { unreachable!() };
-    Ok(Int(ns / 1_000_000i64))
+    match rep("(undefined-symbol 1)", &env) {
+        Err(MalErr::Unbound(s)) => assert_eq!(s, "undefined-symbol"),
+        r => panic!("Expected an unbound symbol error, got: {:?}", r),
+    }
+    match rep("(/ 1 0)", &env) {
+        Err(MalErr::DivisionByZero) => (),
+        r => panic!("Expected division by zero, got: {:?}", r),
+    }
+    match rep("((fn* (a b) a) 1)", &env) {
+        Err(e) => match e.kind() {
+            MalErr::Arity(_) => (),
+            _ => panic!("Expected an arity error, got: {:?}", e),
+        },
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
+    }
+    match rep("(+ 1 \"a\")", &env) {
+        Err(MalErr::Type(_)) => (),
+        r => panic!("Expected a type error, got: {:?}", r),
+    }
+    match rep("(throw {:code 42})", &env) {
+        Err(MalErr::Throw(v)) => assert_eq!(v.pr_str(true), "{:code 42}"),
+        r => panic!("Expected a thrown value, got: {:?}", r),
+    }
 }
 
-fn time_s(a: MalArgs) -> MalRet {
-    let Int(ns) = time_ns(a)? else { unreachable!() };
-    Ok(Int(ns / 1_000_000_000i64))
+#[test]
+fn try_catch_errors_as_values() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    match rep("(try* (throw [1 2]) (catch* e e))", &env) {
+        Ok(s) => assert_eq!(s, "[1 2]"),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+    match rep("(try* (/ 1 0) (catch* e e))", &env) {
+        Ok(s) => assert_eq!(s, "\"division by zero\""),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+    match rep("(try* abc (catch* e e))", &env) {
+        Ok(s) => assert_eq!(s, "\"'abc' not found\""),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
 }
 
-/// Simple readline implementation using standard input
-/// This doesn't have history or line editing features
-pub fn simple_readline(prompt: &str) -> Option<String> {
-    print!("{}", prompt);
-    io::stdout().flush().ok()?;
+#[test]
+fn stack_trace() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    rep("(def! inner (fn* (x) (+ x \"a\")))", &env).unwrap();
+    rep("(def! outer (fn* (x) (+ 1 (inner x))))", &env).unwrap();
 
-    let mut line = String::new();
-    match io::stdin().read_line(&mut line) {
-        Ok(0) => None, // EOF
-        Ok(_) => {
-            // Remove trailing newline
-            while line.ends_with('\n') || line.ends_with('\r') {
-                line.pop();
-            }
-            Some(line)
+    match rep("(outer 1)", &env) {
+        Err(e) => {
+            let names: Vec<_> = e.trace().iter().map(|f| f.name.as_deref().unwrap()).collect();
+            assert_eq!(names, vec!["inner", "outer"]);
+            let span = e.trace()[0].span().expect("call form should carry a span");
+            assert_eq!((span.line, span.column), (1, 27));
+            assert_eq!(
+                format!("{:#}", e),
+                "expecting (int/float, int/float, ...) args\n  at inner (inner x) (1:27)\n  at outer (outer 1) (1:1)"
+            );
         }
-        Err(_) => None,
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
     }
-}
 
-fn readline(p: &str) -> MalRet {
-    match simple_readline(p) {
-        Some(s) => Ok(Str(s)),
-        None => Ok(Nil),
+    match rep("(try* (outer 1) (catch* e (map (fn* (f) (get f :name)) *stack-trace*)))", &env) {
+        Ok(s) => assert_eq!(s, "(\"inner\" \"outer\")"),
+        Err(e) => panic!("rep() returned an error: {}", e),
     }
 }
 
-// SAFETY: This is safe because:
-// 1. This is a single-threaded application (no concurrent access)
-// 2. GLOBAL_ENV is only written once during initialization in main()
-// 3. After initialization, it's only read (immutable access)
-// 4. We need a global variable because func() only accepts function pointers
-//    without captures, so eval_wrapper cannot capture the environment directly
-static mut GLOBAL_ENV: Option<&'static mal::Env> = None;
+#[test]
+fn fuel_limit() {
+    use mal::{env_fuel, env_set_fuel, eval_with_fuel, read};
+
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+    rep("(def! f (fn* () (f)))", &env).unwrap();
+
+    env_set_fuel(&env, Some(10_000));
+    match rep("(try* (f) (catch* e :caught))", &env) {
+        Err(e) => match e.kind() {
+            MalErr::BudgetExhausted => (),
+            _ => panic!("Expected budget exhaustion, got: {:?}", e),
+        },
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
+    }
+    assert_eq!(env_fuel(&env), Some(0));
+
+    env_set_fuel(&env, None);
+    match eval_with_fuel(&read("(f)").unwrap(), &env, 100) {
+        Err(e) => assert!(matches!(e.kind(), MalErr::BudgetExhausted)),
+        Ok(v) => panic!("Should have returned an error, but got: {}", v.pr_str(true)),
+    }
+    assert_eq!(env_fuel(&env), None);
+    match eval_with_fuel(&read("(+ 1 2)").unwrap(), &env, 100) {
+        Ok(v) => assert_eq!(v.pr_str(true), "3"),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
 
-fn eval_wrapper(a: MalArgs) -> MalRet {
-    if a.len() != 1 {
-        return error("eval requires exactly 1 argument");
+    // Natives looping over items charge a step per item. `(range 900)` fits
+    // in the budget on its own, the loop consuming it does not.
+    for code in ["(range 100000000000)", "(reduce + (range 900))", "(sort (range 900))", "(partition 1 (range 900))"] {
+        env_set_fuel(&env, Some(1_500));
+        check_err(&env, &[code], |e| matches!(e, MalErr::BudgetExhausted));
     }
-    // SAFETY: Safe because GLOBAL_ENV is initialized before eval_wrapper is called
-    let env = unsafe { GLOBAL_ENV.unwrap() };
-    mal::eval(&a[0], env)
+    env_set_fuel(&env, Some(1_500));
+    check(&env, &[("(count (range 900))", "900")]);
 }
 
-#[macro_use(fn_str)]
-extern crate mal;
+#[test]
+fn eval_builtin_uses_repl_env() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    match rep("(do (def! y 5) (eval (read-string \"(+ y 10)\")))", &env) {
+        Ok(s) => assert_eq!(s, "15"),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+}
 
 #[test]
-fn eval_eval() {
-    use mal::{initialize_mal_env, mal_env, rep, env_sets};
-    use mal::printer::pr_seq;
+fn depth_limit() {
+    use mal::env_set_max_depth;
 
     let env = mal_env();
     initialize_mal_env(&env, vec![]);
+    env_set_max_depth(&env, Some(100));
+    rep("(def! sum (fn* (n) (if (= n 0) 0 (+ n (sum (- n 1))))))", &env).unwrap();
 
-    env_sets(&env, "slurp", func(slurp));
-    env_sets(&env, "time/ns", func(time_ns));
-    env_sets(&env, "time/ms", func(time_ms));
-    env_sets(&env, "time/us", func(time_us));
-    env_sets(&env, "time/s", func(time_s));
-    env_sets(&env, "prn", func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }));
-    env_sets(&env, "println", func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),);
-    env_sets(&env, "readline", func(fn_str!(readline)));
+    match rep("(sum 100000)", &env) {
+        Err(e) => assert!(matches!(e.kind(), MalErr::DepthExceeded(100))),
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
+    }
+    match rep("(try* (sum 100000) (catch* e \"too deep\"))", &env) {
+        Ok(s) => assert_eq!(s, "\"too deep\""),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+    match rep("(sum 10)", &env) {
+        Ok(s) => assert_eq!(s, "55"),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+}
 
-    // Leak env to get a 'static reference, then set eval
-    let env_static: &'static mal::Env = Box::leak(Box::new(env.clone()));
-    unsafe { GLOBAL_ENV = Some(env_static); }
-    env_sets(&env, "eval", func(eval_wrapper));
+#[test]
+fn default_depth_limit() {
+    let env = mal::mal_env_bytecode();
+    initialize_mal_env(&env, vec![]);
+    rep("(def! sum (fn* (n) (if (= n 0) 0 (+ n (sum (- n 1))))))", &env).unwrap();
 
-    match rep("(eval (read-string \"(+ 1 10)\"))", &env) {
-        Ok(s) => assert_eq!(s, "11"),
-        Err(_) => panic!("rep() returned an error"),
+    check(&env, &[("(sum 1000)", "500500")]);
+    check_err(&env, &["(sum 100000)"], |e| matches!(e, MalErr::DepthExceeded(mal::DEFAULT_MAX_DEPTH)));
+}
+
+#[test]
+fn nesting_limit() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let deep = "(".repeat(100_000);
+    let nested = format!("{}1{}", "[".repeat(200), "]".repeat(200));
+    match rep(&format!("{}1{}", deep, ")".repeat(100_000)), &env) {
+        Err(e) => assert!(matches!(e, MalErr::Syntax(_)), "unexpected error: {}", e),
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
     }
+    check_err(&env, &[&deep], |e| matches!(e, MalErr::Syntax(_)));
+    check(&env, &[(&format!("(= '{0} '{0})", nested), "true"), (&format!("(count (pr-str '{}))", nested), "401")]);
+    // An atom holding itself prints `(atom ` 1025 times, then `...`.
+    check(&env, &[("(let* [a (atom nil)] (do (reset! a a) (count (pr-str a))))", "7178")]);
 }
 
 #[test]
-fn eval_fn() {
+fn interrupt_hook() {
+    use mal::env_set_interrupt;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
     let env = mal_env();
     initialize_mal_env(&env, vec![]);
+    rep("(def! spin (fn* (n) (spin (+ n 1))))", &env).unwrap();
 
-    match rep("((fn* (a b) (+ a b)) 5 7)", &env) {
-        Ok(s) => assert_eq!(s, "12"),
-        Err(_) => panic!("rep() returned an error"),
+    let flag = Rc::new(Cell::new(false));
+    let polls = Rc::new(Cell::new(0));
+    let (f, p) = (flag.clone(), polls.clone());
+    env_set_interrupt(&env, Some(Rc::new(move || {
+        p.set(p.get() + 1);
+        if p.get() == 5_000 {
+            f.set(true);
+        }
+        f.replace(false)
+    })));
+
+    match rep("(try* (map spin [1]) (catch* e :caught))", &env) {
+        Err(e) => assert!(matches!(e.kind(), MalErr::Interrupted)),
+        Ok(s) => panic!("Should have returned an error, but got: {}", s),
     }
+    assert!(!flag.get());
 
+    // The environment is still usable after the interruption.
+    match rep("(+ 1 2)", &env) {
+        Ok(s) => assert_eq!(s, "3"),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+
+    // Natives poll the hook too, so loops that never evaluate Lisp code
+    // can be stopped. The hook fires only after as many polls as the small
+    // variant takes, so only the natives' own polls can trigger it.
+    let cases = [
+        ("(range 100000000)", "(range 1)"),
+        ("(reduce + (range 100000000))", "(reduce + (range 1))"),
+        ("(sort (range 3000 0 -1))", "(sort (range 1 0 -1))"),
+    ];
+    for (code, small) in cases {
+        let p = polls.clone();
+        env_set_interrupt(&env, Some(Rc::new(move || {
+            p.set(p.get() + 1);
+            false
+        })));
+        polls.set(0);
+        rep(small, &env).unwrap();
+        let limit = polls.get();
+        let p = polls.clone();
+        env_set_interrupt(&env, Some(Rc::new(move || {
+            p.set(p.get() + 1);
+            p.get() > limit
+        })));
+        polls.set(0);
+        match rep(code, &env) {
+            Err(e) => assert!(matches!(e.kind(), MalErr::Interrupted), "{}", code),
+            Ok(s) => panic!("Should have returned an error for {}, but got: {}", code, s),
+        }
+    }
 }
 
 #[test]
-fn eval_fn_insuficient_args() {
+fn interned_symbols() {
+    use mal::{read, Symbol};
+    use mal::types::MalVal::{Kwd, Sym};
+
+    let a = Symbol::intern("sensor");
+    let b = Symbol::intern(&String::from("sensor"));
+    assert_eq!(a, b);
+    assert!(std::ptr::eq(a.as_str(), b.as_str()));
+    assert_ne!(a, Symbol::intern("sensors"));
+    // Hashing uses a hash of the name taken when it is interned, not where
+    // it is stored, so map order is the same on every run.
+    use std::hash::BuildHasher;
+    let hasher = std::hash::BuildHasherDefault::<std::collections::hash_map::DefaultHasher>::default();
+    assert_eq!(hasher.hash_one(a), hasher.hash_one(b));
+    assert_ne!(hasher.hash_one(a), hasher.hash_one(Symbol::intern("sensors")));
+
+    match (read("sensor"), read(":sensor")) {
+        (Ok(Sym(s)), Ok(Kwd(k))) => {
+            assert_eq!(s, a);
+            assert_eq!(k, a);
+        }
+        _ => panic!("expected a symbol and a keyword"),
+    }
+
     let env = mal_env();
     initialize_mal_env(&env, vec![]);
+    match rep("[(= 'abc (symbol \"abc\")) (= :abc (keyword \"abc\")) (= 'abc :abc) (get {:abc 1} (keyword \"abc\"))]", &env) {
+        Ok(s) => assert_eq!(s, "[true true false 1]"),
+        Err(e) => panic!("rep() returned an error: {}", e),
+    }
+}
 
-    // Should error because function expects 2 arguments but got 0
-    match rep("((fn* (a b) (+ a b)))", &env) {
+#[test]
+fn hash_map_any_keys() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(get (hash-map 1 :one [2 3] :coords 'sym :s nil :nil) [2 3])", ":coords"),
+        ("(get (hash-map 1 :one) 1.0)", ":one"),
+        ("(get {[1 2] :v} '(1 2))", ":v"),
+        ("(get (assoc {} {:a 1 :b 2} :m) {:b 2 :a 1})", ":m"),
+        ("(get (assoc {} 'sym 1) 'sym)", "1"),
+        ("(contains? (dissoc (hash-map 1 2 3 4) 1) 1)", "false"),
+        ("(keys (hash-map [1] 2))", "([1])"),
+        ("(hash-map \"a\" 1)", "{\"a\" 1}"),
+        ("(= (hash-map :a 1 \"a\" 2 'a 3) {'a 3 \"a\" 2 :a 1})", "true"),
+        ("(count (keys (hash-map :a 1 \"a\" 2 'a 3)))", "3"),
+        ("(let* (k 7) {k (+ k 1)})", "{7 8}"),
+        ("(pr-str (hash-map [1 \"x\"] nil))", "\"{[1 \\\"x\\\"] nil}\""),
+    ];
+    check(&env, &cases);
+}
+
+#[test]
+fn exact_equality() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(= 9007199254740992 9007199254740992.0)", "true"),
+        ("(= 9007199254740993 9007199254740992.0)", "false"),
+        ("(= 1180591620717411303424 1180591620717411303424.0)", "true"),
+        ("(= 0 -0.0)", "true"),
+        ("(= 1/2 0.5)", "true"),
+        ("(= 1/3 (/ 1.0 3))", "false"),
+        ("(get (hash-map 1/2 :half) 0.5)", ":half"),
+        ("(get (hash-map 1180591620717411303424.0 :big) 1180591620717411303424)", ":big"),
+        ("(get (hash-map -0.0 :zero) 0)", ":zero"),
+        // NaN is not `=` to itself, but is found again as a key.
+        ("(= ##NaN ##NaN)", "false"),
+        ("(= [##NaN] [##NaN])", "false"),
+        ("(get (hash-map ##NaN :nan) (- ##Inf ##Inf))", ":nan"),
+        ("(count (set [##NaN ##NaN]))", "1"),
+        // Functions and atoms are equal only to themselves.
+        ("(let* (f (fn* (x) x)) [(= f f) (= f (fn* (x) x)) (get {f 1} f)])", "[true false 1]"),
+        ("[(= + +) (= + -)]", "[true false]"),
+        ("(let* (a (atom 1)) [(= a a) (= a (atom 1))])", "[true false]"),
+    ];
+    check(&env, &cases);
+}
+
+#[test]
+fn persistent_collections() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(let* (v [1 2] w (conj v 3)) [v w])", "[[1 2] [1 2 3]]"),
+        ("(let* (m {:a 1} n (assoc m :b 2) o (dissoc n :a)) [m (get n :b) o])", "[{:a 1} 2 {:b 2}]"),
+        ("(= [1 2 3] (conj [1] 2 3) '(1 2 3))", "true"),
+        ("(nth (vec (list 1 2 3)) 2)", "3"),
+        ("(first [])", "nil"),
+        ("(rest [1 2 3])", "(2 3)"),
+    ];
+    check(&env, &cases);
+
+    rep("(def! fill (fn* (n v m) (if (= n 0) [v m] (fill (- n 1) (conj v n) (assoc m n (* n n))))))", &env).unwrap();
+    check(&env, &[("(let* (r (fill 20000 [] {})) [(count (nth r 0)) (nth (nth r 0) 0) (get (nth r 1) 300)])", "[20000 20000 90000]")]);
+}
+
+#[test]
+fn sets() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("#{1}", "#{1}"),
+        ("(count #{1 2 (+ 1 1) :a})", "3"),
+        ("(= #{1 2 3} (hash-set 3 2 1) (set [1 2 3 2]))", "true"),
+        ("(= #{1} [1])", "false"),
+        ("(set? #{})", "true"),
+        ("(contains? (conj #{} :a :b) :b)", "true"),
+        ("(contains? (disj #{:a :b} :b) :b)", "false"),
+        ("(= (union #{1 2} #{2 3}) #{1 2 3})", "true"),
+        ("(intersection #{1 2 3} #{2 3 4} #{3 4})", "#{3}"),
+        ("(= (difference #{1 2 3} #{2} #{5}) #{1 3})", "true"),
+        ("(get {#{1 2} :found} #{2 1})", ":found"),
+        ("'#{x}", "#{x}"),
+        ("(seq #{})", "nil"),
+    ];
+    check(&env, &cases);
+    check_err(&env, &["(read-string \"# {1}\")", "#{1 # 2}"], |e| matches!(e, MalErr::Syntax(_)));
+}
+
+#[cfg(not(feature = "f32"))]
+#[test]
+fn double_precision_floats() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("1700000000.25", "1700000000.25"),
+        ("(+ 1700000000.5 0.25)", "1700000000.75"),
+        ("(= 16777217 16777217.0)", "true"),
+        ("(< 16777216 16777217.0)", "true"),
+        ("(* 0.1 3)", "0.30000000000000004"),
+    ];
+    check(&env, &cases);
+}
+
+#[test]
+fn big_integers() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    rep("(def! fact (fn* (n) (if (= n 0) 1 (* n (fact (- n 1))))))", &env).unwrap();
+    let cases = [
+        ("(fact 25)", "15511210043330985984000000"),
+        ("(/ (fact 25) (fact 23))", "600"),
+        ("(int? (/ (fact 25) (fact 23)))", "true"),
+        ("(+ 9223372036854775807 1)", "9223372036854775808"),
+        ("(- -9223372036854775808 1)", "-9223372036854775809"),
+        ("(/ -9223372036854775808 -1)", "9223372036854775808"),
+        ("(- 9223372036854775808 1)", "9223372036854775807"),
+        ("123N", "123"),
+        ("(= 5N 5)", "true"),
+        ("(< 9223372036854775807 99999999999999999999)", "true"),
+        ("(> 99999999999999999999 1.5)", "true"),
+        ("(= 99999999999999999999 (+ 99999999999999999998 1))", "true"),
+        ("(get {99999999999999999999 :big} (* 99999999999999999999 1))", ":big"),
+        ("(float? (+ 99999999999999999999 0.5))", "true"),
+        ("(int 1180591620717411303424.0)", "1180591620717411303424"),
+    ];
+    check(&env, &cases);
+}
+
+#[test]
+fn ratios() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(/ 1 3)", "1/3"),
+        ("(/ 6 3)", "2"),
+        ("(/ 4 -6)", "-2/3"),
+        ("(+ 1/3 1/6)", "1/2"),
+        ("(+ 1/3 2/3)", "1"),
+        ("(int? (* 2/3 3/2))", "true"),
+        ("(- 1 1/4)", "3/4"),
+        ("(* 1/4 0.5)", "0.125"),
+        ("(/ 1/3 2)", "1/6"),
+        ("(/ 99999999999999999999 2)", "99999999999999999999/2"),
+        ("6/4", "3/2"),
+        ("(= 1/2 2/4 0.5)", "true"),
+        ("(= 1/3 1)", "false"),
+        ("(< 1/3 0.34)", "true"),
+        ("(>= 2/3 2/3)", "true"),
+        ("(> 1/3 1/2)", "false"),
+        ("(<= 1/3 0)", "false"),
+        ("(get {1/2 :half} 0.5)", ":half"),
+        ("[(ratio? 1/2) (ratio? 2/2) (number? 1/2)]", "[true false true]"),
+        ("[(numerator 6/4) (denominator 6/4) (int 7/2) (float 1/4)]", "[3 2 3 0.25]"),
+        ("(read-string \"1/2\")", "1/2"),
+        ("/", "#<builtin>"),
+    ];
+    check(&env, &cases);
+
+    check_err(&env, &["1/0"], |e| matches!(e, MalErr::Syntax(_)));
+}
+
+#[test]
+fn numeric_literals() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("0xFF", "255"),
+        ("-0x10", "-16"),
+        ("0b1010", "10"),
+        ("0o17", "15"),
+        ("0xFFFFFFFFFFFFFFFFFF", "4722366482869645213695"),
+        ("1_000_000", "1000000"),
+        ("+5", "5"),
+        ("-5", "-5"),
+        ("1e6", "1000000.0"),
+        ("1.5E-3", "0.0015"),
+        (".5", "0.5"),
+        ("-.5", "-0.5"),
+        ("(+ 1.5 1.5)", "3.0"),
+        ("1e30", "1e30"),
+        ("-3/6", "-1/2"),
+        ("##Inf", "##Inf"),
+        ("(- ##Inf)", "##-Inf"),
+        ("(- ##-Inf)", "##Inf"),
+        ("(- 5)", "-5"),
+        ("(- 0.0)", "-0.0"),
+        ("(- -9223372036854775808)", "9223372036854775808"),
+        ("(- 100000000000000000000)", "-100000000000000000000"),
+        ("(- -1/3)", "1/3"),
+        ("(* -1 ##Inf)", "##-Inf"),
+        ("(= ##NaN ##NaN)", "false"),
+        ("(str ##NaN)", "\"##NaN\""),
+        ("(= 1e3 (read-string (pr-str 1e3)))", "true"),
+        ("(float? (read-string (pr-str 2.0)))", "true"),
+        ("(map symbol? '(- + -> 1x 0xZZ 1_ ...))", "(true true true true true true true)"),
+    ];
+    check(&env, &cases);
+}
+
+#[test]
+fn chars() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("\\a", "\\a"),
+        ("[\\newline \\space \\tab \\é \\u00e9]", "[\\newline \\space \\tab \\é \\é]"),
+        ("(= \\é \\u00E9)", "true"),
+        ("[\\( \\] \\{]", "[\\( \\] \\{]"),
+        ("(seq \"héllo\")", "(\\h \\é \\l \\l \\o)"),
+        ("(nth \"héllo\" 1)", "\\é"),
+        ("[(get \"abc\" 2) (get \"abc\" 3)]", "[\\c nil]"),
+        ("(apply str (seq \"abc\"))", "\"abc\""),
+        ("(str \\a \\space \\b)", "\"a b\""),
+        ("[(char? \\a) (char? \"a\")]", "[true false]"),
+        ("[(char->int \\A) (int->char 97) (char 233)]", "[65 \\a \\é]"),
+        ("[(char-upper \\a) (char-lower \\É) (char-upper \\ß)]", "[\\A \\é \\ß]"),
+        ("[(char-alphabetic? \\a) (char-numeric? \\7) (char-whitespace? \\newline)]", "[true true true]"),
+        ("(int->char 0)", "\\u0000"),
+        ("(read-string (pr-str \\newline))", "\\newline"),
+    ];
+    check(&env, &cases);
+
+    check_err(&env, &["\\bogus"], |e| matches!(e, MalErr::Syntax(_)));
+}
+
+#[test]
+fn string_library() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(count \"héllo\")", "5"),
+        ("(empty? \"\")", "true"),
+        ("[(subs \"héllo\" 1) (subs \"héllo\" 1 3) (subs \"abc\" 3)]", "[\"éllo\" \"él\" \"\"]"),
+        ("(string->number \"42\")", "42"),
+        ("[(string->number \" 0xFF \") (string->number \"1/2\") (string->number \"1e3\")]", "[255 1/2 1000.0]"),
+        ("(string->number \"abc\")", "nil"),
+        ("[(str/subs \"héllo\" 1 3) (str/string->number \"42\")]", "[\"él\" 42]"),
+        ("(str/split \"a,b,,c\" \",\")", "[\"a\" \"b\" \"\" \"c\"]"),
+        ("(str/split \"a b\" \\space)", "[\"a\" \"b\"]"),
+        ("(str/split-lines \"one\\ntwo\\r\\nthree\")", "[\"one\" \"two\" \"three\"]"),
+        ("(str/join \", \" [1 \"b\" :c])", "\"1, b, :c\""),
+        ("(str/join (list \"a\" \"b\"))", "\"ab\""),
+        ("[(str/upper-case \"straße\") (str/lower-case \"ÀB\") (str/capitalize \"hELLO\")]", "[\"STRASSE\" \"àb\" \"Hello\"]"),
+        ("[(str/trim \"  x \") (str/triml \" x \") (str/trimr \" x \")]", "[\"x\" \"x \" \" x\"]"),
+        ("[(str/blank? \" \\t\") (str/blank? \" a\")]", "[true false]"),
+        ("[(str/starts-with? \"hello\" \"he\") (str/ends-with? \"hello\" \\o) (str/includes? \"hello\" \"xx\")]", "[true true false]"),
+        ("[(str/index-of \"héllo\" \\l) (str/index-of \"héllo\" \"l\" 3) (str/last-index-of \"héllo\" \"l\") (str/index-of \"abc\" \"z\")]", "[2 3 3 nil]"),
+        ("(str/replace \"a-b-c\" \"-\" \"+\")", "\"a+b+c\""),
+        ("(str/reverse \"héllo\")", "\"olléh\""),
+    ];
+    check(&env, &cases);
+
+    check_err(&env, &["(subs \"abc\" 2 1)"], |e| matches!(e, MalErr::Other(_)));
+    check_err(&env, &["(subs \"a\")", "(str/split \"a\")", "(str/replace \"a\" \"b\")"], |e| matches!(e, MalErr::Arity(_)));
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn regexes() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("#\"\\d+\"", "#\"\\d+\""),
+        ("(str #\"a|b\")", "\"a|b\""),
+        ("(regex? (re-pattern \"[a-z]+\"))", "true"),
+        ("(= #\"x*\" (re-pattern \"x*\"))", "true"),
+        ("(re-find #\"\\d+\" \"abc 123 def 45\")", "\"123\""),
+        ("(re-find #\"(\\w+)=(\\d+)?\" \"key= x\")", "[\"key=\" \"key\" nil]"),
+        ("(re-find #\"\\d\" \"none\")", "nil"),
+        ("(re-matches #\"a|ab\" \"ab\")", "\"ab\""),
+        ("(re-matches #\"\\d+\" \"123x\")", "nil"),
+        ("(re-matches #\"(\\d+)-(\\d+)\" \"10-20\")", "[\"10-20\" \"10\" \"20\"]"),
+        ("(re-seq #\"\\d+\" \"1 22 333\")", "(\"1\" \"22\" \"333\")"),
+        ("(re-seq #\"\\d\" \"abc\")", "nil"),
+        ("(re-find #\"é+\" \"caféé!\")", "\"éé\""),
+        ("(re-find #\"say \\\"(\\w+)\\\"\" \"they say \\\"hi\\\"\")", "[\"say \\\"hi\\\"\" \"hi\"]"),
+        ("(str/split \"a1b22c\" #\"\\d+\")", "[\"a\" \"b\" \"c\"]"),
+        ("(str/replace \"2024-01-31\" #\"(\\d+)-(\\d+)-(\\d+)\" \"$3/$2/$1\")", "\"31/01/2024\""),
+        ("(str/replace \"a1b2\" #\"\\d\" (fn* (d) (str \"<\" d \">\")))", "\"a<1>b<2>\""),
+        ("(get {#\"a\" 1} #\"a\")", "1"),
+    ];
+    check(&env, &cases);
+
+    check_err(&env, &["#\"(\""], |e| matches!(e, MalErr::Syntax(_)));
+}
+
+#[cfg(not(feature = "regex"))]
+#[test]
+fn regexes_disabled() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    check_err(&env, &["#\"a\""], |e| matches!(e, MalErr::Syntax(_)));
+}
+
+#[test]
+fn sequence_library() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(map + [1 2 3] '(10 20))", "(11 22)"),
+        ("(map (fn* (e) (nth e 1)) {:a 1})", "(1)"),
+        ("(map char-upper \"ab\")", "(\\A \\B)"),
+        ("(mapcat (fn* (x) [x x]) [1 2])", "(1 1 2 2)"),
+        ("(filter (fn* (x) (> x 1)) #{1})", "()"),
+        ("[(filter int? [1 :a 2]) (remove int? [1 :a 2])]", "[(1 2) (:a)]"),
+        ("[(reduce + [1 2 3]) (reduce + 10 [1 2 3]) (reduce + [])]", "[6 16 0]"),
+        ("(reduce (fn* (m e) (assoc m (nth e 1) (nth e 0))) {} {:a 1})", "{1 :a}"),
+        ("[(range 3) (range 1 4) (range 10 0 -3) (range 0 1 1/2)]", "[(0 1 2) (1 2 3) (10 7 4 1) (0 1/2)]"),
+        ("[(range 9223372036854775806 9223372036854775807 5) (range 0 1 0.5) (range 2 -1)]", "[(9223372036854775806) (0 0.5) ()]"),
+        ("[(take 2 [1 2 3]) (drop 2 [1 2 3]) (take 5 nil)]", "[(1 2) (3) ()]"),
+        ("[(take-while int? [1 2 :x 3]) (drop-while int? [1 2 :x 3])]", "[(1 2) (:x 3)]"),
+        ("[(partition 2 [1 2 3 4 5]) (partition 2 1 [1 2 3])]", "[((1 2) (3 4)) ((1 2) (2 3))]"),
+        ("(interleave [1 2 3] \"ab\")", "(1 \\a 2 \\b)"),
+        ("(reverse [1 2 3])", "(3 2 1)"),
+        ("(sort [3 1/2 2.5 -1])", "(-1 1/2 2.5 3)"),
+        ("(sort [\"b\" \"a\" \"c\"])", "(\"a\" \"b\" \"c\")"),
+        ("(sort > [1 3 2])", "(3 2 1)"),
+        ("(sort (fn* (a b) (compare b a)) [[1 2] [1] [0 5]])", "([1 2] [1] [0 5])"),
+        ("(sort-by count [\"ccc\" \"a\" \"bb\"])", "(\"a\" \"bb\" \"ccc\")"),
+        ("(sort-by first > [[1 :a] [2 :b] [1 :c]])", "([2 :b] [1 :a] [1 :c])"),
+        ("(get (group-by odd? [1 2 3]) true)", "[1 3]"),
+        ("(get (frequencies \"hello\") \\l)", "2"),
+        ("[(some (fn* (x) (and (> x 1) x)) [1 2 3]) (some int? [:a])]", "[2 nil]"),
+        ("[(every? int? [1 2]) (every? int? [1 :a]) (every? int? [])]", "[true false true]"),
+        ("(distinct [1 2 1 3 2 1.0])", "(1 2 3)"),
+        ("(get (zipmap [:a :b] [1 2 3]) :b)", "2"),
+        ("[(compare 1 2) (compare \"b\" \"a\") (compare nil 1)]", "[-1 1 -1]"),
+    ];
+    rep("(def! odd? (fn* (n) (ratio? (/ n 2))))", &env).unwrap();
+    rep("(defmacro! and (fn* (a b) `(if ~a ~b ~a)))", &env).unwrap();
+    check(&env, &cases);
+
+    check_err(&env, &["(sort [1 :a])"], |e| matches!(e, MalErr::Type(_)));
+    check_err(&env, &["(reduce +)", "(partition 2)", "(sort)", "(sort-by first)", "(map +)", "(take 1)", "(compare 1)"], |e| matches!(e, MalErr::Arity(_)));
+}
+
+#[test]
+fn map_library() {
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+
+    rep("(def! cfg {:db {:host \"h\" :ports [5432 5433]} :debug false})", &env).unwrap();
+    let cases = [
+        ("(get-in cfg [:db :host])", "\"h\""),
+        ("(get-in cfg [:db :ports 1])", "5433"),
+        ("(get-in cfg [:db :user] \"root\")", "\"root\""),
+        ("[(get-in {:a nil} [:a] 5) (get-in {:a {:b false}} [:a :b] 5) (get-in {:a 1} [:a :b] 5)]", "[nil false 5]"),
+        ("(get cfg :debug :default)", "false"),
+        ("(get-in (assoc-in cfg [:db :host] \"x\") [:db :host])", "\"x\""),
+        ("(assoc-in {} [:a :b] 1)", "{:a {:b 1}}"),
+        ("(get-in (assoc-in cfg [:db :ports 0] 1) [:db :ports])", "[1 5433]"),
+        ("(update {:n 1} :n + 10)", "{:n 11}"),
+        ("(update {} :n (fn* (x) (if x x 0)))", "{:n 0}"),
+        ("(get-in (update-in cfg [:db :ports] conj 5434) [:db :ports])", "[5432 5433 5434]"),
+        ("(= (merge {:a 1 :b 1} nil {:b 2 :c 3}) {:a 1 :b 2 :c 3})", "true"),
+        ("(merge)", "nil"),
+        ("(= (merge-with + {:a 1 :b 2} {:a 10}) {:a 11 :b 2})", "true"),
+        ("(= (select-keys {:a 1 :b 2 :c 3} [:a :c :z]) {:a 1 :c 3})", "true"),
+        ("(select-keys nil [:a])", "{}"),
+        ("[(find {:a nil} :a) (find {} :a)]", "[[:a nil] nil]"),
+        ("(= (into {} [[:a 1] [:b 2]]) {:a 1 :b 2})", "true"),
+        ("(into [1] '(2 3))", "[1 2 3]"),
+        ("(into () [1 2])", "(2 1)"),
+        ("(= (into {:a 1} {:b 2}) {:a 1 :b 2})", "true"),
+        ("(seq {:a 1})", "([:a 1])"),
+        ("(seq {})", "nil"),
+        ("[(count {:a 1 :b 2}) (empty? {})]", "[2 true]"),
+        ("(assoc [1 2] 2 3)", "[1 2 3]"),
+        ("(zipmap [:a] [1])", "{:a 1}"),
+    ];
+    check(&env, &cases);
+    check_err(&env, &["(update {})", "(merge-with)", "(get-in {})", "(assoc-in {} [:a])", "(get {})", "(into [])"], |e| matches!(e, MalErr::Arity(_)));
+    check_err(&env, &["(select-keys [1 2] [0])", "(find [1 2] 0)"], |e| matches!(e, MalErr::Type(_)));
+}
+
+#[test]
+fn system_ops() {
+    let sys = mal::MemSystemOps::new()
+        .with_file("lib.mal", "(def! double (fn* (x) (* 2 x)))")
+        .with_input("mal");
+    sys.set_time_ms(86_400_000);
+    let env = mal::mal_env_with_system(std::rc::Rc::new(sys));
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(slurp \"lib.mal\")", "\"(def! double (fn* (x) (* 2 x)))\""),
+        ("(do (load-file \"lib.mal\") (double 21))", "42"),
+        ("(readline \"name> \")", "\"mal\""),
+        ("(readline \"name> \")", "nil"),
+        ("[(time/s) (time/ms) (time/us)]", "[86400 86400000 86400000000]"),
+    ];
+    check(&env, &cases);
+    check_err(&env, &["(slurp \"missing.mal\")", "(list-dir \"lib.mal\")", "(mkdir \"lib.mal\")"], |e| matches!(e, MalErr::Fs(_)));
+    match rep("(slurp \"missing.mal\")", &env) {
+        Err(e) => match e.kind() {
+            MalErr::Fs(fs) => assert_eq!((fs.kind, fs.path.as_str()), (mal::FsErrorKind::NotFound, "missing.mal")),
+            _ => panic!("Expected a filesystem error, got: {:?}", e),
+        },
         Ok(s) => panic!("Should have returned an error, but got: {}", s),
-        Err(_) => (), // Expected error
     }
+    check(&env, &[("(try* (slurp \"missing.mal\") (catch* e [(get e :kind) (get e :path) (get e :message)]))", "[:not-found \"missing.mal\" \"not found\"]")]);
+    check_err(&env, &["(time/ms 1)"], |e| matches!(e, MalErr::Arity(_)));
+
+    // Without a `SystemOps` there is nothing to load files from.
+    let env = mal_env();
+    initialize_mal_env(&env, vec![]);
+    check_err(&env, &["(load-file \"lib.mal\")"], |e| matches!(e, MalErr::Unbound(_)));
 }
 
 #[test]
-fn eval_fn_too_many_args() {
-    let env = mal_env();
+fn output() {
+    let sys = std::rc::Rc::new(mal::MemSystemOps::new());
+    let env = mal::mal_env_with_system(sys.clone());
     initialize_mal_env(&env, vec![]);
 
-    // Should error because function expects 2 arguments but got 0
-    match rep("((fn* (a b) (+ a b)) 1 2 3)", &env) {
+    let code = r#"(do (prn "a" \b 1) (println "a" \b 1) (pr "x") (print "x") (newline) (flush))"#;
+    match rep(code, &env) {
+        Ok(s) => assert_eq!(s, "nil"),
+        Err(e) => panic!("rep() returned an error for {}: {}", code, e),
+    }
+    assert_eq!(sys.output(), "\"a\" \\b 1\na b 1\n\"x\"x\n");
+
+    let cases = [
+        ("(with-out-str (print \"a\") (prn [1 \"b\"]))", "\"a[1 \\\"b\\\"]\\n\""),
+        ("(with-out-str)", "\"\""),
+        ("(with-out-str (print 1 (with-out-str (print 2))) (print 3))", "\"1 23\""),
+        ("(try* (with-out-str (print 1) (throw 2)) (catch* e e))", "2"),
+    ];
+    check(&env, &cases);
+    rep("(print \"after\")", &env).unwrap();
+    assert!(sys.output().ends_with("\"x\"x\nafter"));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn std_time() {
+    let env = mal::mal_env_with_system(std::rc::Rc::new(mal::StdSystemOps::new(None)));
+    initialize_mal_env(&env, vec![]);
+
+    // Every `time/*` function counts from the UNIX epoch.
+    check(&env, &[("(let* (ms (time/ms) ns (time/ns)) (< (- (/ ns 1000000) ms) 1000))", "true")]);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn std_filesystem() {
+    let dir = std::env::temp_dir().join(format!("mal-fs-{}", std::process::id()));
+    let dir = dir.to_str().unwrap().replace('\\', "/");
+    let env = mal::mal_env_with_system(std::rc::Rc::new(mal::StdSystemOps::new(None)));
+    initialize_mal_env(&env, vec![]);
+    rep(&format!("(def! dir \"{}\")", dir), &env).unwrap();
+
+    let cases = [
+        ("(file-exists? dir)", "false"),
+        ("(mkdir (str dir \"/sub/deeper\"))", "nil"),
+        ("(spit (str dir \"/a.mal\") \"(def! x 1)\")", "nil"),
+        ("(append-file (str dir \"/a.mal\") '(def! y 2))", "nil"),
+        ("(slurp (str dir \"/a.mal\"))", "\"(def! x 1)(def! y 2)\""),
+        ("(do (load-file (str dir \"/a.mal\")) (+ x y))", "3"),
+        ("(list-dir dir)", "[\"a.mal\" \"sub\"]"),
+        ("(delete-file (str dir \"/sub/deeper\"))", "nil"),
+        ("(list-dir (str dir \"/sub\"))", "[]"),
+        ("(file-exists? (str dir \"/a.mal\"))", "true"),
+    ];
+    check(&env, &cases);
+    check_err(&env, &["(delete-file dir)"], |e| matches!(e, MalErr::Fs(_)));
+    std::fs::remove_dir_all(&dir).unwrap();
+    match rep("(slurp (str dir \"/a.mal\"))", &env) {
         Ok(s) => panic!("Should have returned an error, but got: {}", s),
-        Err(_) => (), // Expected error
+        Err(e) => assert!(e.to_string().starts_with(&format!("{}/a.mal: ", dir)), "{}", e),
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn memory_filesystem() {
+    let sys = std::rc::Rc::new(
+        mal::MemSystemOps::new()
+            .with_file("src/lib.mal", "(def! x 1)")
+            .with_file("/etc/motd", "hi"),
+    );
+    let env = mal::mal_env_with_system(sys.clone());
+    initialize_mal_env(&env, vec![]);
+
+    let cases = [
+        ("(do (load-file \"./src//lib.mal\") x)", "1"),
+        ("(spit \"out/a.txt\" [1 \"b\"])", "nil"),
+        ("(append-file \"out/a.txt\" \"!\")", "nil"),
+        ("(slurp \"out/sub/../a.txt\")", "\"[1 b]!\""),
+        ("(list-dir \".\")", "[\"out\" \"src\"]"),
+        ("(list-dir \"/\")", "[\"etc\"]"),
+        ("(mkdir \"out/empty/deeper\")", "nil"),
+        ("(list-dir \"out\")", "[\"a.txt\" \"empty\"]"),
+        ("[(file-exists? \"out/empty\") (file-exists? \"nope\")]", "[true false]"),
+        ("(delete-file \"out/empty/deeper\")", "nil"),
+        ("(delete-file \"out/empty\")", "nil"),
+        ("(try* (delete-file \"out\") (catch* e (get e :kind)))", ":directory-not-empty"),
+        ("(try* (slurp \"src\") (catch* e (get e :kind)))", ":is-a-directory"),
+        ("(try* (spit \"src/lib.mal/x\" 1) (catch* e [(get e :kind) (get e :path)]))", "[:not-a-directory \"src/lib.mal/x\"]"),
+        ("(try* (delete-file \"gone\") (catch* e (get e :kind)))", ":not-found"),
+    ];
+    check(&env, &cases);
+    assert_eq!(sys.file("out/a.txt").as_deref(), Some("[1 b]!"));
+    assert_eq!(sys.paths(), ["/etc/motd", "out/a.txt", "src/lib.mal"]);
+}
@@ -1,4 +1,4 @@
-use mal::reader::{CharReader, MalStream, TokenStream};
+use mal::reader::{CharReader, MalStream, Token, TokenStream};
 use mal::types::MalVal;
 
 #[test]
@@ -9,7 +9,7 @@ fn test_token_stream_simple() {
 
     // This is a simplified test - TokenStream implementation needs refinement
     // for proper token-by-token iteration
-    let tokens: Vec<String> = token_stream.collect();
+    let tokens: Vec<Token> = token_stream.collect();
     assert!(!tokens.is_empty());
 }
 
@@ -25,7 +25,7 @@ fn test_mal_stream_single_expression() {
             let result = val.pr_str(true);
             assert_eq!(result, "(+ 1 2)");
         }
-        Some(Err(e)) => panic!("Got error: {}", e.pr_str(true)),
+        Some(Err(e)) => panic!("Got error: {}", e),
         None => panic!("Expected an expression"),
     }
 
@@ -48,7 +48,7 @@ fn test_mal_stream_multiple_expressions() {
     for (i, result) in results.iter().enumerate() {
         match result {
             Ok(_) => {}, // Success
-            Err(e) => panic!("Expression {} failed: {}", i, e.pr_str(true)),
+            Err(e) => panic!("Expression {} failed: {}", i, e),
         }
     }
 }
@@ -64,7 +64,7 @@ fn test_mal_stream_incomplete_expression() {
             // Should successfully parse the complete expression
             assert!(val.pr_str(true).contains("+"));
         }
-        Some(Err(e)) => panic!("Got error: {}", e.pr_str(true)),
+        Some(Err(e)) => panic!("Got error: {}", e),
         None => panic!("Expected an expression"),
     }
 }
@@ -94,7 +94,7 @@ fn test_mal_stream_error_handling() {
 
     match mal_stream.next() {
         Some(Err(e)) => {
-            let err_msg = e.pr_str(false);
+            let err_msg = e.to_string();
             // Should get an incomplete or EOF error
             assert!(err_msg.contains("EOF") || err_msg.contains("expected"));
         }
@@ -174,11 +174,11 @@ fn test_example_usage() {
             Ok(expr) => {
                 match mal::eval(&expr, &env) {
                     Ok(val) => println!("Result {}: {}", i + 1, val.pr_str(true)),
-                    Err(e) => eprintln!("Error evaluating expression {}: {}", i + 1, e.pr_str(true)),
+                    Err(e) => eprintln!("Error evaluating expression {}: {}", i + 1, e),
                 }
             }
             Err(e) => {
-                eprintln!("Error in expression {}: {}", i + 1, e.pr_str(true));
+                eprintln!("Error in expression {}: {}", i + 1, e);
             }
         }
     }
@@ -253,7 +253,7 @@ fn test_rep_stream_to_malret() {
                 count += 1;
             }
             Ok(_) => panic!("Expected Int"),
-            Err(e) => panic!("Unexpected error: {}", e.pr_str(true)),
+            Err(e) => panic!("Unexpected error: {}", e),
         }
     }
 
@@ -295,3 +295,18 @@ fn test_rep_stream_to_malret_vec() {
         panic!("Expected Int(50)");
     }
 }
+
+#[test]
+fn test_mal_stream_spans_with_file() {
+    let input = "(+ 1 2)\n  (* 3\n     4)";
+    let mal_stream = MalStream::with_file(input.chars(), "rules.lisp");
+
+    let spans: Vec<String> = mal_stream
+        .map(|r| match r {
+            Ok(form) => form.span().expect("form should carry a span").to_string(),
+            Err(e) => panic!("Got error: {}", e),
+        })
+        .collect();
+
+    assert_eq!(spans, vec!["rules.lisp:1:1", "rules.lisp:2:3"]);
+}
@@ -0,0 +1,198 @@
+use mal::{initialize_mal_env, mal_env, mal_env_bytecode, rep, Env, MalErr};
+
+// The AST walker and the bytecode VM must agree on every program, so each
+// test runs against one environment of each kind.
+fn envs() -> [Env; 2] {
+    let tree = mal_env();
+    initialize_mal_env(&tree, vec![]);
+    let vm = mal_env_bytecode();
+    initialize_mal_env(&vm, vec![]);
+    [tree, vm]
+}
+
+fn check(env: &Env, code: &str, expected: &str) {
+    match rep(code, env) {
+        Ok(s) => assert_eq!(s, expected, "{}", code),
+        Err(e) => panic!("rep() returned an error for {}: {}", code, e),
+    }
+}
+
+#[test]
+fn special_forms() {
+    for env in envs() {
+        check(&env, "(def! x 3)", "3");
+        check(&env, "(let* (x 4 y (+ x 1)) (* x y))", "20");
+        check(&env, "x", "3");
+        check(&env, "(if nil 1 2)", "2");
+        check(&env, "(if false 1)", "nil");
+        check(&env, "(if 0 1 2)", "1");
+        check(&env, "(do)", "nil");
+        check(&env, "(do (def! y 1) (def! y (+ y 1)) y)", "2");
+        check(&env, "'(a b)", "(a b)");
+        check(&env, "[x (+ x 1) {:k (* x 2)}]", "[3 4 {:k 6}]");
+        check(&env, "#{(+ x 1)}", "#{4}");
+        check(&env, "`(1 ~x ~@(list 2 3) [~x])", "(1 3 2 3 [3])");
+    }
+}
+
+#[test]
+fn closures_and_tail_calls() {
+    for env in envs() {
+        rep("(def! adder (fn* (n) (fn* (m) (+ n m))))", &env).unwrap();
+        check(&env, "((adder 2) 5)", "7");
+        check(&env, "(map (adder 10) [1 2 3])", "(11 12 13)");
+        rep("(def! count-down (fn* (n) (if (= n 0) :done (count-down (- n 1)))))", &env).unwrap();
+        check(&env, "(count-down 100000)", ":done");
+        rep("(def! f (fn* (a & more) (list a more)))", &env).unwrap();
+        check(&env, "(f 1 2 3)", "(1 (2 3))");
+        check(&env, "(apply + 1 [2 3])", "6");
+    }
+}
+
+#[test]
+fn macros() {
+    for env in envs() {
+        check(&env, "(cond false 1 nil 2 :else 3)", "3");
+        rep("(defmacro! unless (fn* (c a b) `(if ~c ~b ~a)))", &env).unwrap();
+        check(&env, "(unless false 1 2)", "1");
+        // Defined after the function using it, so only known at run time.
+        rep("(def! g (fn* () (twice 4)))", &env).unwrap();
+        rep("(defmacro! twice (fn* (x) `(+ ~x ~x)))", &env).unwrap();
+        check(&env, "(g)", "8");
+        // A local binding shadows a macro of the same name.
+        check(&env, "(let* (unless (fn* (a b c) :fn)) (unless 1 2 3))", ":fn");
+    }
+}
+
+#[test]
+fn lexical_scopes() {
+    for env in envs() {
+        check(&env, "(let* (x 1 x (+ x 1)) x)", "2");
+        check(&env, "(let* (x 1) [(let* (x 2) x) x])", "[2 1]");
+        check(&env, "((fn* (a a) a) 1 2)", "2");
+        check(&env, "(let* (x 1) ((fn* (y) (let* (z 3) (+ x y z))) 2))", "6");
+        // `def!` inside a function binds in its own environment.
+        check(&env, "((fn* (x) (do (def! x (* x 10)) (def! y 1) (+ x y))) 4)", "41");
+        check(&env, "(let* (n 5) (do (def! n (+ n 1)) n))", "6");
+        // A `def!` that does not run leaves the global visible.
+        rep("(def! g 1)", &env).unwrap();
+        check(&env, "(let* (c false) (do (if c (def! g 2)) g))", "1");
+        check(&env, "((fn* (c) (do (if c (def! g 2)) [g ((fn* () g))])) true)", "[2 2]");
+        check(&env, "g", "1");
+        // A macro only known at run time expands into code using locals.
+        rep("(def! h (fn* (v) (let* (w 2) (late v w))))", &env).unwrap();
+        rep("(defmacro! late (fn* (a b) `(* ~a ~b)))", &env).unwrap();
+        check(&env, "(h 21)", "42");
+        // A local it defines is visible to the rest of the caller and does
+        // not clobber the caller's own locals.
+        rep("(def! k (fn* (v) (do (late-def) (def! y 7) [v x y])))", &env).unwrap();
+        rep("(defmacro! late-def (fn* () '(def! x 5)))", &env).unwrap();
+        check(&env, "(k 1)", "[1 5 7]");
+        rep("(def! make (fn* (n acc) (if (= n 0) acc (make (- n 1) (cons (fn* () n) acc)))))", &env).unwrap();
+        check(&env, "(map (fn* (f) (f)) (make 3 ()))", "(1 2 3)");
+        check(&env, "(let* (e 1) (try* (throw 2) (catch* e [e *stack-trace*])))", "[2 []]");
+    }
+}
+
+#[test]
+fn conditional_shadowing_def() {
+    // The one place the two disagree: a `def!` shadowing an enclosing local
+    // takes a slot under the VM, which reads as `nil` until the `def!` runs.
+    let [tree, vm] = envs();
+    let code = "(let* (x 1) (let* (c false) (do (if c (def! x 2)) x)))";
+    check(&tree, code, "1");
+    check(&vm, code, "nil");
+    for env in [tree, vm] {
+        check(&env, "(let* (x 1) (let* (c true) (do (if c (def! x 2)) x)))", "2");
+    }
+}
+
+#[test]
+fn try_catch() {
+    for env in envs() {
+        check(&env, "(try* (throw {:a 1}) (catch* e (get e :a)))", "1");
+        check(&env, "(try* (nope) (catch* e e))", "\"'nope' not found\"");
+        check(&env, "(try* 1 (catch* e 2))", "1");
+        check(&env, "(try* (+ 1 (try* (throw 1) (catch* e (+ e 1)))) (catch* e :outer))", "3");
+        rep("(def! boom (fn* (n) (if (= n 0) (throw :deep) (+ 1 (boom (- n 1))))))", &env).unwrap();
+        check(&env, "(let* (r (try* (boom 5) (catch* e e))) [r r])", "[:deep :deep]");
+        check(&env, "(map (fn* (x) (try* (/ 1 x) (catch* e :div))) [1 0])", "(1 :div)");
+    }
+}
+
+#[test]
+fn errors_and_traces() {
+    for env in envs() {
+        rep("(def! inner (fn* (x) (+ x \"a\")))", &env).unwrap();
+        rep("(def! outer (fn* (x) (+ 1 (inner x))))", &env).unwrap();
+        match rep("(outer 1)", &env) {
+            Err(e) => {
+                assert!(matches!(e.kind(), MalErr::Type(_)));
+                assert_eq!(
+                    format!("{:#}", e),
+                    "expecting (int/float, int/float, ...) args\n  at inner (inner x) (1:27)\n  at outer (outer 1) (1:1)"
+                );
+            }
+            Ok(s) => panic!("Should have returned an error, but got: {}", s),
+        }
+        check(&env, "(try* (outer 1) (catch* e (map (fn* (f) (get f :name)) *stack-trace*)))", "(\"inner\" \"outer\")");
+        match rep("((fn* (a) a))", &env) {
+            Err(e) => assert!(matches!(e.kind(), MalErr::Arity(_))),
+            Ok(s) => panic!("Should have returned an error, but got: {}", s),
+        }
+    }
+}
+
+#[test]
+fn limits() {
+    use mal::{env_set_fuel, env_set_max_depth};
+
+    for env in envs() {
+        rep("(def! spin (fn* () (spin)))", &env).unwrap();
+        env_set_fuel(&env, Some(1_000));
+        match rep("(try* (spin) (catch* e :caught))", &env) {
+            Err(e) => assert!(matches!(e.kind(), MalErr::BudgetExhausted)),
+            Ok(s) => panic!("Should have returned an error, but got: {}", s),
+        }
+        env_set_fuel(&env, None);
+
+        env_set_max_depth(&env, Some(100));
+        rep("(def! sum (fn* (n) (if (= n 0) 0 (+ n (sum (- n 1))))))", &env).unwrap();
+        check(&env, "(try* (sum 1000) (catch* e \"too deep\"))", "\"too deep\"");
+        check(&env, "(sum 10)", "55");
+    }
+}
+
+#[test]
+fn eval_and_load() {
+    for env in envs() {
+        check(&env, "(eval (list '+ 1 2))", "3");
+        check(&env, "(eval (read-string \"(let* (a 2) (* a a))\"))", "4");
+        check(&env, "(def! a (atom 1))", "(atom 1)");
+        check(&env, "(swap! a (fn* (x y) (+ x y)) 5)", "6");
+    }
+}
+
+#[test]
+fn vm_functions_in_tree_code() {
+    use mal::{env_set, env_set_max_depth, eval, reader::read_str, Sym, Symbol};
+
+    let [tree, vm] = envs();
+    for (name, code) in [
+        ("vm-f", "(fn* (a b) (let* (c (* a b)) (+ c a)))"),
+        ("vm-adder", "(fn* (n) (fn* (m) (+ n m)))"),
+        ("vm-sum", "(fn* (n) (if (= n 0) 0 (+ n (vm-sum (- n 1)))))"),
+    ] {
+        let f = eval(&read_str(code).unwrap(), &vm).unwrap();
+        env_set(&vm, &Sym(Symbol::intern(name)), f.clone()).unwrap();
+        env_set(&tree, &Sym(Symbol::intern(name)), f).unwrap();
+    }
+    // Recursion inside a VM function counts only the VM's call frames.
+    env_set_max_depth(&vm, Some(100));
+    check(&tree, "(vm-sum 80)", "3240");
+
+    check(&tree, "(vm-f 3 4)", "15");
+    check(&tree, "(let* (g vm-f) (g 2 5))", "12");
+    check(&tree, "((vm-adder 2) 5)", "7");
+    check(&tree, "(map (vm-adder 10) [1 2 3])", "(11 12 13)");
+}
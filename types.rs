@@ -2,36 +2,103 @@ use alloc::rc::Rc;
 use core::cell::RefCell;
 #[allow(unused_imports)]
 use alloc::vec;
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::string::{String, ToString};
 use alloc::format;
+use core::fmt;
+use core::hash::{self, Hash as _, Hasher};
 
 use itertools::Itertools;
 
-use crate::FnvHashMap;
+use fnv::{FnvBuildHasher, FnvHasher};
+use archery::RcK;
+use num_traits::{FromPrimitive, ToPrimitive};
 
+use crate::compiler::Code;
 use crate::env::Env;
-use crate::types::MalVal::{Bool, Func, Hash, Int, Float, Kwd, List, MalFunc, NativeClosure, Nil, Str, Sym, Vector};
+use crate::symbol::Symbol;
+use crate::system::FsError;
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Char, Func, Hash, Int, Float, Kwd, List, MalFunc, NativeClosure, Nil, Ratio, Set, Str, Sym,
+    Vector,
+};
 
 // Function closures and atoms may create cyclic dependencies, so
 // reference counting should be replaced at least for these two kinds
 // of references.
 
+/// Persistent vector: `conj` and `assoc` share structure with the original.
+pub type MalVector = rpds::Vector<MalVal>;
+/// Persistent hash map (a HAMT): `assoc` and `dissoc` share structure with
+/// the original.
+pub type MalMap = rpds::HashTrieMap<MalVal, MalVal, RcK, FnvBuildHasher>;
+
+/// Persistent hash set, built like `MalMap`.
+pub type MalSet = rpds::HashTrieSet<MalVal, RcK, FnvBuildHasher>;
+
+/// An empty map.
+pub fn new_map() -> MalMap {
+    MalMap::new_with_hasher_and_ptr_kind(FnvBuildHasher::default())
+}
+
+/// How deeply forms and collections may nest. The reader rejects deeper
+/// input; the compiler, `quasiquote` and hashing recurse on the native stack
+/// once per level and stop here, and the printer prints deeper values (such
+/// as an atom holding itself) as `...`. Like `DEFAULT_MAX_DEPTH`, this is
+/// sized for optimized builds.
+pub const MAX_NESTING: usize = 1024;
+
+/// Error for forms nested deeper than `MAX_NESTING`.
+pub(crate) fn nesting_error<T>() -> Result<T, MalErr> {
+    syntax_error(&format!("forms nested more than {} deep", MAX_NESTING))
+}
+
+/// An empty set.
+pub fn new_set() -> MalSet {
+    MalSet::new_with_hasher_with_ptr_kind(FnvBuildHasher::default())
+}
+
+/// Floating point type of `MalVal::Float`: `f64`, or `f32` with the `f32`
+/// feature.
+#[cfg(not(feature = "f32"))]
+pub type MalFloat = f64;
+/// Floating point type of `MalVal::Float`: `f64`, or `f32` with the `f32`
+/// feature.
+#[cfg(feature = "f32")]
+pub type MalFloat = f32;
+
+/// Arbitrary-precision integer of `MalVal::BigInt`.
+pub type MalBigInt = num_bigint::BigInt;
+/// Exact fraction of `MalVal::Ratio`.
+pub type MalRatio = num_rational::BigRational;
+
 #[derive(Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    Float(f32),
+    /// An integer outside the range of `i64`; build it with `bigint` so
+    /// that smaller values stay `Int`.
+    BigInt(MalBigInt),
+    /// A fraction in lowest terms that is not an integer; build it with
+    /// `ratio` so that whole results become integers.
+    Ratio(MalRatio),
+    Float(MalFloat),
     Str(String),
-    Sym(String),
-    Kwd(String),
+    Char(char),
+    Sym(Symbol),
+    Kwd(Symbol),
     List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
+    Vector(MalVector, Rc<MalVal>),
+    Hash(MalMap, Rc<MalVal>),
+    Set(MalSet, Rc<MalVal>),
     Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
     MalFunc(FuncStruct),
     NativeClosure(Rc<dyn Fn(MalArgs) -> MalRet>, Rc<MalVal>),
+    #[cfg(feature = "regex")]
+    Regex(Rc<crate::regex::MalRegex>),
     Atom(Rc<RefCell<MalVal>>),
 }
 
@@ -42,10 +109,74 @@ pub struct FuncStruct {
     pub params: Rc<MalVal>,
     pub is_macro: bool,
     pub meta: Rc<MalVal>,
+    /// Name the function was first bound to with `def!`/`defmacro!`.
+    pub name: Option<Rc<str>>,
+    /// Bytecode for the body, compiled on the first call made through the VM.
+    pub code: Rc<Code>,
 }
 
 pub type MalArgs = Vec<MalVal>;
-pub type MalRet = Result<MalVal, MalVal>;
+pub type MalRet = Result<MalVal, MalErr>;
+
+/// Error raised while reading or evaluating MAL code.
+///
+/// Lisp code sees every error as a value: `catch*` binds the thrown value for
+/// `Throw`, a map for `Fs` (see `FsError`) and the error message as a string
+/// for every other kind.
+#[derive(Clone)]
+pub enum MalErr {
+    /// The input ended in the middle of a form; more input may complete it.
+    Incomplete(String),
+    /// Malformed source or special form.
+    Syntax(String),
+    /// A symbol with no binding in the environment.
+    Unbound(String),
+    /// A function called with the wrong number of arguments.
+    Arity(String),
+    /// An argument of the wrong type.
+    Type(String),
+    DivisionByZero,
+    /// A value raised by `throw`.
+    Throw(MalVal),
+    /// A failure reported by the host (time, output...).
+    Io(String),
+    /// A failed filesystem operation.
+    Fs(FsError),
+    /// Any other evaluation error.
+    Other(String),
+    /// Evaluation nested deeper than the limit set with `env_set_max_depth`.
+    DepthExceeded(usize),
+    /// The evaluation step budget set with `env_set_fuel` ran out.
+    /// `catch*` does not intercept it.
+    BudgetExhausted,
+    /// The callback set with `env_set_interrupt` asked to stop.
+    /// `catch*` does not intercept it.
+    Interrupted,
+    /// An error together with the Lisp calls it unwound through, innermost first.
+    Traced(Box<MalErr>, Vec<Frame>),
+}
+
+/// One Lisp function call on the stack of a failed evaluation.
+#[derive(Clone)]
+pub struct Frame {
+    pub name: Option<Rc<str>>,
+    /// The call form, when the call was made from source rather than `apply`.
+    pub form: Option<MalVal>,
+}
+
+/// Source location of a token or of a form read from source.
+///
+/// Lines and columns are 1-based; `end_line`/`end_column` point at the last
+/// character covered, not one past it. Forms carry their span in the `meta`
+/// slot as `{:line :column :end-line :end-column}` plus `:file` when known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
 
 // type utility macros
 
@@ -58,8 +189,20 @@ macro_rules! list {
 
 // type utility functions
 
-pub fn error<T>(s: &str) -> Result<T, MalVal> {
-    Err(Str(s.to_string()))
+pub fn error<T>(s: &str) -> Result<T, MalErr> {
+    Err(MalErr::Other(s.to_string()))
+}
+
+pub fn syntax_error<T>(s: &str) -> Result<T, MalErr> {
+    Err(MalErr::Syntax(s.to_string()))
+}
+
+pub fn arity_error<T>(s: &str) -> Result<T, MalErr> {
+    Err(MalErr::Arity(s.to_string()))
+}
+
+pub fn type_error<T>(s: &str) -> Result<T, MalErr> {
+    Err(MalErr::Type(s.to_string()))
 }
 
 pub fn list(seq: MalArgs) -> MalVal {
@@ -67,32 +210,234 @@ pub fn list(seq: MalArgs) -> MalVal {
 }
 
 pub fn vector(seq: MalArgs) -> MalVal {
-    Vector(Rc::new(seq), Rc::new(Nil))
+    Vector(seq.into_iter().collect(), Rc::new(Nil))
 }
 
+/// `n` as an `Int` if it fits in an `i64`, or else as a `BigInt`.
+pub fn bigint(n: MalBigInt) -> MalVal {
+    match n.to_i64() {
+        Some(i) => Int(i),
+        None => BigInt(n),
+    }
+}
+
+/// Nearest float to `n`, infinite if out of range.
+pub fn bigint_to_float(n: &MalBigInt) -> MalFloat {
+    n.to_f64().unwrap_or(f64::NAN) as MalFloat
+}
+
+/// `f` widened to `f64`.
+#[cfg(not(feature = "f32"))]
+pub fn float_to_f64(f: MalFloat) -> f64 {
+    f
+}
+
+/// `f` widened to `f64`.
+#[cfg(feature = "f32")]
+pub fn float_to_f64(f: MalFloat) -> f64 {
+    f as f64
+}
+
+/// `r` as an integer if its denominator is 1, or else as a `Ratio`.
+pub fn ratio(r: MalRatio) -> MalVal {
+    if r.is_integer() {
+        bigint(r.to_integer())
+    } else {
+        Ratio(r)
+    }
+}
+
+/// Nearest float to `r`.
+pub fn ratio_to_float(r: &MalRatio) -> MalFloat {
+    r.to_f64().unwrap_or(f64::NAN) as MalFloat
+}
+
+impl MalVal {
+    /// Elements of a list or vector. Lists are borrowed; vectors are copied
+    /// out of their trie.
+    pub fn as_seq(&self) -> Option<Cow<'_, [MalVal]>> {
+        match self {
+            List(l, _) => Some(Cow::Borrowed(&l[..])),
+            Vector(v, _) => Some(Cow::Owned(v.iter().cloned().collect())),
+            _ => None,
+        }
+    }
+}
+
+// The integer `f` is, as an `Int` or a `BigInt`; `None` unless `f` is
+// finite and integral.
+fn float_as_integer(f: MalFloat) -> Option<MalVal> {
+    // 2^63: an integral float smaller than this converts to `i64` exactly.
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    let g = float_to_f64(f);
+    if (-LIMIT..LIMIT).contains(&g) {
+        let i = g as i64;
+        (i as f64 == g).then_some(Int(i))
+    } else {
+        // Floats this large are all integral.
+        MalBigInt::from_f64(g).map(BigInt)
+    }
+}
+
+impl MalVal {
+    /// `=`: like `==`, except that NaN is not equal to anything, not even
+    /// itself.
+    pub fn equals(&self, other: &MalVal) -> bool {
+        self.equal(other, false)
+    }
+
+    // Compares nested collections through a work list rather than
+    // recursion, so comparing deeply nested values cannot overflow the
+    // native stack.
+    fn equal(&self, other: &MalVal, nan_equal: bool) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            let equal = match (a, b) {
+                (Nil, Nil) => true,
+                (Bool(a), Bool(b)) => a == b,
+                (Int(a), Int(b)) => a == b,
+                (Float(a), Float(b)) => a == b || (nan_equal && a.is_nan() && b.is_nan()),
+                // Floats equal other numbers only when they hold exactly the
+                // same value, which keeps `=` transitive.
+                (Int(a), Float(b)) | (Float(b), Int(a)) => matches!(float_as_integer(*b), Some(Int(i)) if i == *a),
+                (BigInt(a), BigInt(b)) => a == b,
+                (BigInt(a), Float(b)) | (Float(b), BigInt(a)) => {
+                    matches!(float_as_integer(*b), Some(BigInt(n)) if n == *a)
+                }
+                (Ratio(a), Ratio(b)) => a == b,
+                (Ratio(a), Float(b)) | (Float(b), Ratio(a)) => MalRatio::from_float(*b).as_ref() == Some(a),
+
+                (Str(a), Str(b)) => a == b,
+                (Char(a), Char(b)) => a == b,
+                #[cfg(feature = "regex")]
+                (MalVal::Regex(a), MalVal::Regex(b)) => a.as_str() == b.as_str(),
+                (Sym(a), Sym(b)) => a == b,
+                (Kwd(a), Kwd(b)) => a == b,
+                (List(a, _), List(b, _)) if a.len() == b.len() => {
+                    pending.extend(a.iter().zip(b.iter()));
+                    true
+                }
+                (Vector(a, _), Vector(b, _)) if a.len() == b.len() => {
+                    pending.extend(a.iter().zip(b.iter()));
+                    true
+                }
+                (List(a, _), Vector(b, _)) | (Vector(b, _), List(a, _)) if a.len() == b.len() => {
+                    pending.extend(a.iter().zip(b.iter()));
+                    true
+                }
+                (Hash(a, _), Hash(b, _)) if a.size() == b.size() => a.iter().all(|(k, v)| match b.get(k) {
+                    Some(w) => {
+                        pending.push((v, w));
+                        true
+                    }
+                    None => false,
+                }),
+                (Set(a, _), Set(b, _)) => a.size() == b.size() && a.iter().all(|v| b.contains(v)),
+
+                // Functions and atoms are equal only to themselves.
+                (Func(a, _), Func(b, _)) => core::ptr::fn_addr_eq(*a, *b),
+                (MalFunc(a), MalFunc(b)) => {
+                    Rc::ptr_eq(&a.ast, &b.ast)
+                        && Rc::ptr_eq(&a.params, &b.params)
+                        && Rc::ptr_eq(&a.env, &b.env)
+                        && a.is_macro == b.is_macro
+                }
+                (NativeClosure(a, _), NativeClosure(b, _)) => Rc::ptr_eq(a, b),
+                (Atom(a), Atom(b)) => Rc::ptr_eq(a, b),
+                _ => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Equality of map keys and set members. Unlike `=`, every NaN equals every
+// other, so that `==` is reflexive and NaN keys can be found again.
 impl PartialEq for MalVal {
     fn eq(&self, other: &MalVal) -> bool {
-        match (self, other) {
-            (Nil, Nil) => true,
-            (Bool(a), Bool(b)) => a == b,
-            (Int(a), Int(b)) => a == b,
-            (Float(a), Float(b)) => a == b,
+        self.equal(other, true)
+    }
+}
+
+impl Eq for MalVal {}
+
+// Bits of an infinity; every NaN gets the same ones.
+#[cfg(not(feature = "f32"))]
+fn float_bits(f: MalFloat) -> u64 {
+    if f.is_nan() { MalFloat::NAN.to_bits() } else { f.to_bits() }
+}
+
+#[cfg(feature = "f32")]
+fn float_bits(f: MalFloat) -> u64 {
+    if f.is_nan() { MalFloat::NAN.to_bits() as u64 } else { f.to_bits() as u64 }
+}
 
-            // TODO(jig): I might review these two lines after
-            (Int(a), Float(b)) => (*a as f32) == *b,
-            (Float(a), Int(b)) => *a == (*b as f32),
+// Consistent with `==`: lists and vectors with the same elements hash
+// alike, and a float hashes as the integer or ratio it equals, so that
+// `1` and `1.0` keys collide.
+impl hash::Hash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash_nested(state, 0);
+    }
+}
 
-            (Str(a), Str(b)) => a == b,
-            (Sym(a), Sym(b)) => a == b,
-            (Kwd(a), Kwd(b)) => a == b,
-            (List(a, _), List(b, _))
-            | (Vector(a, _), Vector(b, _))
-            | (List(a, _), Vector(b, _))
-            | (Vector(a, _), List(b, _)) => a == b,
-            (Hash(a, _), Hash(b, _)) => a == b,
-            (MalFunc { .. }, MalFunc { .. }) => false,
-            (NativeClosure(_, _), NativeClosure(_, _)) => false,
-            _ => false,
+impl MalVal {
+    // Hashes `self` found `depth` collections deep. Anything nested deeper
+    // than `MAX_NESTING` is left out, which keeps equal values hashing alike.
+    fn hash_nested<H: Hasher>(&self, state: &mut H, depth: usize) {
+        if depth > MAX_NESTING {
+            return;
+        }
+        match self {
+            Nil => {}
+            Bool(b) => b.hash(state),
+            Int(i) => i.hash(state),
+            Float(f) => match float_as_integer(*f) {
+                Some(n) => n.hash_nested(state, depth),
+                None => match MalRatio::from_float(*f) {
+                    Some(r) => r.hash(state),
+                    None => float_bits(*f).hash(state),
+                },
+            },
+            BigInt(n) => n.hash(state),
+            Ratio(r) => r.hash(state),
+            Str(s) => s.hash(state),
+            Char(c) => c.hash(state),
+            #[cfg(feature = "regex")]
+            MalVal::Regex(r) => r.as_str().hash(state),
+            Sym(s) | Kwd(s) => s.hash(state),
+            List(l, _) => {
+                l.len().hash(state);
+                l.iter().for_each(|v| v.hash_nested(state, depth + 1));
+            }
+            Vector(v, _) => {
+                v.len().hash(state);
+                v.iter().for_each(|v| v.hash_nested(state, depth + 1));
+            }
+            Hash(hm, _) => {
+                // Summed so that the arbitrary iteration order does not matter.
+                let sum = hm.iter().fold(0u64, |acc, (k, v)| {
+                    let mut entry = FnvHasher::default();
+                    k.hash_nested(&mut entry, depth + 1);
+                    v.hash_nested(&mut entry, depth + 1);
+                    acc.wrapping_add(entry.finish())
+                });
+                hm.size().hash(state);
+                sum.hash(state);
+            }
+            Set(s, _) => {
+                let sum = s.iter().fold(0u64, |acc, v| {
+                    let mut entry = FnvHasher::default();
+                    v.hash_nested(&mut entry, depth + 1);
+                    acc.wrapping_add(entry.finish())
+                });
+                s.size().hash(state);
+                sum.hash(state);
+            }
+            _ => {}
         }
     }
 }
@@ -129,32 +474,210 @@ where
     NativeClosure(Rc::new(f), Rc::new(Nil))
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
-        return error("odd number of elements");
+        return arity_error("odd number of elements");
+    }
+    for (k, v) in kvs.into_iter().tuples() {
+        hm.insert_mut(k, v);
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
+}
+
+impl MalErr {
+    /// The value `catch*` binds for this error.
+    pub fn to_value(&self) -> MalVal {
+        match self {
+            MalErr::Throw(v) => v.clone(),
+            MalErr::Fs(e) => e.to_value(),
+            MalErr::Traced(e, _) => e.to_value(),
+            e => Str(e.to_string()),
+        }
+    }
+
+    /// The underlying error, without any stack trace.
+    pub fn kind(&self) -> &MalErr {
+        match self {
+            MalErr::Traced(e, _) => e,
+            e => e,
+        }
+    }
+
+    /// Lisp calls the error unwound through, innermost first.
+    pub fn trace(&self) -> &[Frame] {
+        match self {
+            MalErr::Traced(_, frames) => frames,
+            _ => &[],
+        }
+    }
+
+    /// Whether `catch*` may handle the error; resource limits always
+    /// propagate to the host.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self.kind(), MalErr::BudgetExhausted | MalErr::Interrupted)
+    }
+
+    /// Records that the error unwound through `frame`.
+    pub fn push_frame(self, frame: Frame) -> MalErr {
+        match self {
+            MalErr::Traced(e, mut frames) => {
+                frames.push(frame);
+                MalErr::Traced(e, frames)
+            }
+            e => MalErr::Traced(Box::new(e), vec![frame]),
+        }
     }
-    for (k, v) in kvs.iter().tuples() {
-        hm.insert(wrap_map_key(k)?, v.clone());
+}
+
+impl Frame {
+    /// Source span of the call form, if it was read from source.
+    pub fn span(&self) -> Option<Span> {
+        self.form.as_ref().and_then(|f| f.span())
+    }
+
+    /// The frame as a map `{:name :form}` plus the span keys of `Span::to_meta`.
+    pub fn to_value(&self) -> MalVal {
+        let mut hm = match self.span().map(|s| s.to_meta()) {
+            Some(Hash(hm, _)) => hm,
+            _ => new_map(),
+        };
+        if let Some(name) = &self.name {
+            hm.insert_mut(Kwd(Symbol::intern("name")), Str(name.to_string()));
+        }
+        if let Some(form) = &self.form {
+            hm.insert_mut(Kwd(Symbol::intern("form")), form.clone());
+        }
+        Hash(hm, Rc::new(Nil))
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {}", self.name.as_deref().unwrap_or("<anonymous>"))?;
+        if let Some(form) = &self.form {
+            write!(f, " {}", form.pr_str(true))?;
+        }
+        if let Some(span) = self.span() {
+            write!(f, " ({})", span)?;
+        }
+        Ok(())
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
-pub fn wrap_map_key(k: &MalVal) -> Result<String, MalVal> {
-    match k {
-        Str(s) => Ok(String::from(s)),
-        Kwd(s) => Ok(format!("\u{29e}{}", s)),
-        _ => error("key is not string"),
+impl fmt::Display for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MalErr::Incomplete(s)
+            | MalErr::Syntax(s)
+            | MalErr::Arity(s)
+            | MalErr::Type(s)
+            | MalErr::Io(s)
+            | MalErr::Other(s) => f.write_str(s),
+            MalErr::Unbound(s) => write!(f, "'{}' not found", s),
+            MalErr::Fs(e) => write!(f, "{}", e),
+            MalErr::DivisionByZero => f.write_str("division by zero"),
+            MalErr::DepthExceeded(max) => write!(f, "maximum evaluation depth ({}) exceeded", max),
+            MalErr::BudgetExhausted => f.write_str("evaluation budget exhausted"),
+            MalErr::Interrupted => f.write_str("interrupted"),
+            MalErr::Throw(v) => f.write_str(&v.pr_str(true)),
+            MalErr::Traced(e, frames) => {
+                write!(f, "{}", e)?;
+                // `{:#}` appends the stack trace, one frame per line.
+                if f.alternate() {
+                    for frame in frames {
+                        write!(f, "\n  {}", frame)?;
+                    }
+                }
+                Ok(())
+            }
+        }
     }
 }
 
-pub fn unwrap_map_key(s: &str) -> MalVal {
-    match s.strip_prefix('\u{29e}') {
-        Some(keyword) => Kwd(String::from(keyword)),
-        _ => Str(String::from(s)),
+impl fmt::Debug for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            MalErr::Incomplete(_) => "Incomplete",
+            MalErr::Syntax(_) => "Syntax",
+            MalErr::Unbound(_) => "Unbound",
+            MalErr::Arity(_) => "Arity",
+            MalErr::Type(_) => "Type",
+            MalErr::DivisionByZero => "DivisionByZero",
+            MalErr::Throw(_) => "Throw",
+            MalErr::Io(_) => "Io",
+            MalErr::Fs(_) => "Fs",
+            MalErr::Other(_) => "Other",
+            MalErr::DepthExceeded(_) => "DepthExceeded",
+            MalErr::BudgetExhausted => "BudgetExhausted",
+            MalErr::Interrupted => "Interrupted",
+            MalErr::Traced(e, frames) => {
+                return write!(f, "Traced({:?}, {} frames)", e, frames.len())
+            }
+        };
+        write!(f, "{}({:?})", kind, self.to_string())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MalErr {}
+
+impl Span {
+    /// Builds the metadata map the reader attaches to lists, vectors and maps.
+    pub fn to_meta(&self) -> MalVal {
+        let key = |k| Kwd(Symbol::intern(k));
+        let mut hm = new_map();
+        hm.insert_mut(key("line"), Int(self.line as i64));
+        hm.insert_mut(key("column"), Int(self.column as i64));
+        hm.insert_mut(key("end-line"), Int(self.end_line as i64));
+        hm.insert_mut(key("end-column"), Int(self.end_column as i64));
+        if let Some(file) = &self.file {
+            hm.insert_mut(key("file"), Str(file.clone()));
+        }
+        Hash(hm, Rc::new(Nil))
+    }
+
+    /// Recovers a span from a metadata map built by `to_meta`.
+    pub fn from_meta(meta: &MalVal) -> Option<Span> {
+        let hm = match meta {
+            Hash(hm, _) => hm,
+            _ => return None,
+        };
+        let int = |k| match hm.get(&Kwd(Symbol::intern(k))) {
+            Some(Int(i)) => Some(*i as usize),
+            _ => None,
+        };
+        Some(Span {
+            file: match hm.get(&Kwd(Symbol::intern("file"))) {
+                Some(Str(f)) => Some(f.clone()),
+                _ => None,
+            },
+            line: int("line")?,
+            column: int("column")?,
+            end_line: int("end-line")?,
+            end_column: int("end-column")?,
+        })
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            None => write!(f, "{}:{}", self.line, self.column),
+        }
+    }
+}
+
+impl MalVal {
+    /// Source span of a list, vector, map or set produced by the reader, if any.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => Span::from_meta(meta),
+            _ => None,
+        }
     }
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+    _assoc(new_map(), kvs)
 }
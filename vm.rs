@@ -0,0 +1,329 @@
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::string::ToString;
+
+use itertools::Itertools;
+
+use crate::compiler::{compile, function_code, Chunk, Op};
+use crate::compiler::compile_expansion;
+use crate::env::{
+    env_bind_slots, env_enter, env_get_slot, env_get_sym, env_leave, env_new, env_set, env_set_slot, env_sets,
+    env_tick, Env,
+};
+use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, NativeClosure, Nil, Set, Sym};
+use crate::types::{new_map, type_error, vector, Frame, FuncStruct, MalArgs, MalErr, MalRet, MalVal};
+use crate::{named, stack_trace};
+
+// Stack VM running chunks produced by `compiler.rs`. Calls between MAL
+// functions push a frame instead of recursing on the native stack, so the
+// depth limit counts frames; natives calling back into MAL (`map`, `apply`,
+// `swap!`) start a nested run through `MalVal::apply`.
+
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    env: Env,
+    // Environments to return to when leaving `let*` and `catch*` scopes.
+    scopes: Vec<Env>,
+    // Height of the value stack when the frame was entered.
+    base: usize,
+    // What to record in a stack trace for errors unwinding through the frame.
+    trace: Option<Frame>,
+}
+
+// A `try*` whose body is running.
+struct Handler {
+    // Index of the frame the `try*` belongs to.
+    frame: usize,
+    ip: usize,
+    stack: usize,
+    scopes: usize,
+    env: Env,
+}
+
+struct Vm {
+    frames: Vec<CallFrame>,
+    stack: Vec<MalVal>,
+    handlers: Vec<Handler>,
+    // Error being handled, picked up by the `Catch` instruction.
+    caught: Option<MalErr>,
+}
+
+/// Compiles `ast` and runs it in `env`.
+pub fn eval(ast: &MalVal, env: &Env) -> MalRet {
+    let chunk = compile(ast, env)?;
+    run(chunk, env.clone(), None)
+}
+
+/// Calls the MAL function `f` with `args`.
+pub fn call(f: &FuncStruct, args: MalArgs) -> MalRet {
+    let chunk = function_code(f)?;
+    let trace = Frame {
+        name: f.name.clone(),
+        form: None,
+    };
+    let env = env_bind_slots(f.env.clone(), &f.params, args).map_err(|e| e.push_frame(trace.clone()))?;
+    run(chunk, env, Some(trace))
+}
+
+fn run(chunk: Rc<Chunk>, env: Env, trace: Option<Frame>) -> MalRet {
+    env_tick(&env).map_err(|e| traced(e, &trace))?;
+    env_enter(&env).map_err(|e| traced(e, &trace))?;
+    let mut vm = Vm {
+        frames: vec![CallFrame {
+            chunk,
+            ip: 0,
+            env,
+            scopes: vec![],
+            base: 0,
+            trace,
+        }],
+        stack: vec![],
+        handlers: vec![],
+        caught: None,
+    };
+    loop {
+        match vm.step() {
+            Ok(Some(res)) => return Ok(res),
+            Ok(None) => {}
+            Err(e) => vm.unwind(e)?,
+        }
+    }
+}
+
+fn traced(e: MalErr, trace: &Option<Frame>) -> MalErr {
+    match trace {
+        Some(frame) => e.push_frame(frame.clone()),
+        None => e,
+    }
+}
+
+impl Vm {
+    fn pop(&mut self) -> MalVal {
+        self.stack.pop().unwrap()
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    // Binds a `def!` in the current environment, leaving the value on the
+    // stack.
+    fn define(&mut self, sym: &MalVal, slot: Option<usize>, val: MalVal) -> Result<(), MalErr> {
+        match slot {
+            Some(index) => env_set_slot(&self.frame().env, index, val.clone()),
+            None => {
+                env_set(&self.frame().env, sym, val.clone())?;
+            }
+        }
+        self.stack.push(val);
+        Ok(())
+    }
+
+    fn push_frame(&mut self, chunk: Rc<Chunk>, env: Env, trace: Option<Frame>) -> Result<(), MalErr> {
+        env_enter(&env).map_err(|e| traced(e, &trace))?;
+        self.frames.push(CallFrame {
+            chunk,
+            ip: 0,
+            env,
+            scopes: vec![],
+            base: self.stack.len(),
+            trace,
+        });
+        Ok(())
+    }
+
+    // Runs one instruction, returning the result once the outermost frame
+    // returns.
+    fn step(&mut self) -> Result<Option<MalVal>, MalErr> {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = frame.chunk.clone();
+        let ip = frame.ip;
+        frame.ip += 1;
+        match &chunk.code[ip] {
+            Op::Const(v) => self.stack.push(v.clone()),
+            Op::Load(s) => match env_get_sym(&self.frame().env, *s) {
+                Some(v) => self.stack.push(v),
+                None => return Err(MalErr::Unbound(s.to_string())),
+            },
+            Op::Local(depth, index) => {
+                let val = env_get_slot(&self.frame().env, *depth, *index)?;
+                self.stack.push(val);
+            }
+            Op::Def(sym, slot) => {
+                let val = named(self.pop(), sym);
+                self.define(sym, *slot, val)?;
+            }
+            Op::DefMacro(sym, slot) => {
+                let val = match self.pop() {
+                    MalFunc(f) => named(MalFunc(FuncStruct { is_macro: true, ..f }), sym),
+                    _ => return type_error("set_macro on non-function"),
+                };
+                self.define(sym, *slot, val)?;
+            }
+            Op::Bind(sym) => {
+                let val = self.pop();
+                env_set(&self.frame().env, sym, val)?;
+            }
+            Op::SetLocal(index) => {
+                let val = self.pop();
+                env_set_slot(&self.frame().env, *index, val);
+            }
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Jump(to) => self.frame().ip = *to,
+            Op::JumpIfFalse(to) => {
+                if let Bool(false) | Nil = self.pop() {
+                    self.frame().ip = *to;
+                }
+            }
+            Op::EnterScope => {
+                let frame = self.frame();
+                let env = env_new(Some(frame.env.clone()));
+                frame.scopes.push(core::mem::replace(&mut frame.env, env));
+            }
+            Op::ExitScope => {
+                let frame = self.frame();
+                frame.env = frame.scopes.pop().unwrap();
+            }
+            Op::Lambda(l) => {
+                let env = self.frame().env.clone();
+                self.stack.push(MalFunc(FuncStruct {
+                    ast: l.ast.clone(),
+                    env,
+                    params: l.params.clone(),
+                    is_macro: false,
+                    meta: Rc::new(Nil),
+                    name: None,
+                    code: l.code.clone(),
+                }));
+            }
+            Op::Vector(n) => {
+                let items = self.stack.split_off(self.stack.len() - n);
+                self.stack.push(vector(items));
+            }
+            Op::Hash(n) => {
+                let kvs = self.stack.split_off(self.stack.len() - 2 * n);
+                let mut hm = new_map();
+                for (k, v) in kvs.into_iter().tuples() {
+                    hm.insert_mut(k, v);
+                }
+                self.stack.push(Hash(hm, Rc::new(Nil)));
+            }
+            Op::Set(n) => {
+                let items = self.stack.split_off(self.stack.len() - n);
+                self.stack.push(Set(items.into_iter().collect(), Rc::new(Nil)));
+            }
+            Op::MacroCheck(form, scopes, skip) => {
+                if let Some(MalFunc(FuncStruct { is_macro: true, .. })) = self.stack.last() {
+                    let m = self.pop();
+                    let args = match form {
+                        List(l, _) => l[1..].to_vec(),
+                        _ => unreachable!(),
+                    };
+                    let expansion = m.apply(args)?;
+                    let env = self.frame().env.clone();
+                    let chunk = compile_expansion(&expansion, &env, (**scopes).clone())?;
+                    self.frame().ip = *skip;
+                    self.push_frame(chunk, env, None)?;
+                }
+            }
+            Op::Call(argc, form) | Op::TailCall(argc, form) => {
+                let tail = matches!(chunk.code[ip], Op::TailCall(..));
+                let args = self.stack.split_off(self.stack.len() - argc);
+                let callee = self.pop();
+                env_tick(&self.frame().env)?;
+                match callee {
+                    MalFunc(f) => {
+                        let chunk = function_code(&f)?;
+                        let trace = Frame {
+                            name: f.name.clone(),
+                            form: Some(form.clone()),
+                        };
+                        let env = env_bind_slots(f.env.clone(), &f.params, args)
+                            .map_err(|e| e.push_frame(trace.clone()))?;
+                        if tail {
+                            let frame = self.frame();
+                            frame.chunk = chunk;
+                            frame.ip = 0;
+                            frame.env = env;
+                            frame.scopes.clear();
+                            frame.trace = Some(trace);
+                        } else {
+                            self.push_frame(chunk, env, Some(trace))?;
+                        }
+                    }
+                    f @ (Func(..) | NativeClosure(..)) => {
+                        let res = f.apply(args)?;
+                        self.stack.push(res);
+                    }
+                    _ => return type_error("attempt to call non-function"),
+                }
+            }
+            Op::Try(catch) => {
+                let frame = self.frames.len() - 1;
+                let (env, scopes) = (self.frame().env.clone(), self.frame().scopes.len());
+                self.handlers.push(Handler {
+                    frame,
+                    ip: *catch,
+                    stack: self.stack.len(),
+                    scopes,
+                    env,
+                });
+            }
+            Op::EndTry => {
+                self.handlers.pop();
+            }
+            Op::Catch(sym) => {
+                let exc = self.caught.take().unwrap();
+                let frame = self.frame();
+                let env = env_new(Some(frame.env.clone()));
+                frame.scopes.push(core::mem::replace(&mut frame.env, env.clone()));
+                match sym {
+                    Sym(_) => env_set_slot(&env, 0, exc.to_value()),
+                    _ => return env_set(&env, sym, exc.to_value()).map(|_| None),
+                }
+                env_sets(&env, "*stack-trace*", stack_trace(&exc));
+            }
+            Op::Return => {
+                let res = self.pop();
+                let frame = self.frames.pop().unwrap();
+                env_leave(&frame.env);
+                if self.frames.is_empty() {
+                    return Ok(Some(res));
+                }
+                self.stack.truncate(frame.base);
+                self.stack.push(res);
+            }
+        }
+        Ok(None)
+    }
+
+    // Pops frames until a `try*` in one of them catches `e`, recording each
+    // frame left in the error's trace. Fails with `e` if none does.
+    fn unwind(&mut self, mut e: MalErr) -> Result<(), MalErr> {
+        loop {
+            let top = self.frames.len() - 1;
+            if e.is_catchable()
+                && let Some(h) = self.handlers.pop_if(|h| h.frame == top)
+            {
+                self.stack.truncate(h.stack);
+                let frame = self.frame();
+                frame.scopes.truncate(h.scopes);
+                frame.env = h.env;
+                frame.ip = h.ip;
+                self.caught = Some(e);
+                return Ok(());
+            }
+            self.handlers.retain(|h| h.frame < top);
+            let frame = self.frames.pop().unwrap();
+            env_leave(&frame.env);
+            e = traced(e, &frame.trace);
+            if self.frames.is_empty() {
+                return Err(e);
+            }
+        }
+    }
+}